@@ -1,18 +1,37 @@
+use candid::utils::{ArgumentDecoder, ArgumentEncoder};
 use candid::{CandidType, Encode, Nat};
-use crc::{Algorithm, Crc};
+use futures::future::join_all;
+use ic_cdk::api::call::RejectionCode;
 use ic_cdk::export::{candid, Principal};
 use ic_cdk::print;
 use ic_cdk::storage;
 use ic_cdk_macros::*;
 use serde::{Deserialize, Serialize};
-use std::cell::RefCell;
+use sha2::{Digest, Sha256};
+use slot_router::{
+    balanced_ranges, check_slot_map, key_slot, repaired_slot_map, split_slots, upper_cut,
+    CanisterNodeMap, CanisterStatusType, CreateCanisterArgs, InstallMode, RunGuard, SlotLoad,
+    SlotMap, SlotMapReport, ERR_SHARD_MIGRATING, ERR_SHARD_UNAUTHORIZED, SLOT_SIZE,
+};
+use std::cell::{Cell, RefCell};
+use std::collections::BTreeMap;
+use std::time::Duration;
 
 const USER_WASM: &[u8] = std::include_bytes!("../../../.dfx/local/canisters/likes/likes.wasm");
 const INIT_CYCLES: u64 = 2_000_000_000_000;
-const SLOT_SIZE: u32 = 65536; //Hash slot size
+//Merged shards stay well below the 2 GB at which likes asks for a split
+const MAX_MERGED_BYTES: u64 = 1024 * 1024 * 1024;
+//How often shard balances are checked and topped up
+const SHARD_CHECK_INTERVAL: Duration = Duration::from_secs(300);
+//Never sent to shards, enough to create one more shard
+const CYCLES_RESERVE: u64 = INIT_CYCLES;
 
 thread_local! {
     static NODE_MAP_LISTS : RefCell<CanisterNodeMapList> = RefCell::new(CanisterNodeMapList::new());
+    static BOOTSTRAP_RUNNING: Cell<bool> = const { Cell::new(false) };
+    static UPGRADE_RUNNING: Cell<bool> = const { Cell::new(false) };
+    static CHECK_RUNNING: Cell<bool> = const { Cell::new(false) };
+    static SHARD_FLEET: RefCell<ShardFleet> = RefCell::new(ShardFleet::default());
 }
 
 #[derive(CandidType, Deserialize)]
@@ -25,409 +44,1579 @@ impl CanisterNodeMapList {
     pub fn new() -> Self {
         Self {
             owner: Principal::from_slice(&[]),
-            slot_list: vec![],
+            slot_list: SlotMap::new(),
+            migrations: None,
+            write_policy: None,
+            retiring: None,
+            bootstrap: None,
+            wasms: None,
+            shard_wasm: None,
+            upgrade: None,
+            cycles_policy: None,
         }
     }
 }
 
+impl Default for CanisterNodeMapList {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 #[derive(Clone, CandidType, Serialize, Deserialize)]
 pub struct CanisterNodeMapList {
     owner: Principal,
-    slot_list: Vec<CanisterNodeMap>,
+    slot_list: SlotMap,
+    //splits copied by the source shard, not yet in the slot map
+    migrations: Option<Vec<SlotMigration>>,
+    write_policy: Option<WritePolicy>,
+    //shards merged into a neighbour, deleted once they own no slots
+    retiring: Option<Vec<Principal>>,
+    bootstrap: Option<BootstrapJob>,
+    //uploaded likes modules, USER_WASM is used until one is rolled out
+    wasms: Option<Vec<WasmModule>>,
+    //hash of the module new shards are installed with
+    shard_wasm: Option<Vec<u8>>,
+    upgrade: Option<FleetUpgrade>,
+    cycles_policy: Option<CyclesPolicy>,
+}
+
+//Shards below `threshold` cycles get `top_up` cycles from the allot
+#[derive(Clone, CandidType, Serialize, Deserialize)]
+pub struct CyclesPolicy {
+    threshold: u64,
+    top_up: u64,
+}
+
+impl Default for CyclesPolicy {
+    fn default() -> Self {
+        Self {
+            threshold: INIT_CYCLES / 4,
+            top_up: INIT_CYCLES / 2,
+        }
+    }
+}
+
+#[derive(Clone, CandidType, Deserialize)]
+pub struct ShardHealth {
+    canister_id: Principal,
+    status: Option<CanisterStatusType>,
+    cycles: Option<Nat>,
+    memory: Option<Nat>,
+    module_hash: Option<Vec<u8>>,
+    //cycles sent by the last check
+    topped_up: u64,
+    last_error: Option<String>,
+}
+
+#[derive(Clone, CandidType, Deserialize, Default)]
+pub struct ShardFleet {
+    updated_at: u64,
+    shards: Vec<ShardHealth>,
+    total_cycles: Nat,
+    total_memory: Nat,
+    allot_cycles: u64,
+}
+
+//Creating the first shards, one part per slot range
+#[derive(Clone, CandidType, Serialize, Deserialize)]
+pub struct BootstrapJob {
+    parts: Vec<BootstrapPart>,
+    //the slot map was built from the parts
+    published: bool,
+}
+
+#[derive(Clone, CandidType, Serialize, Deserialize, PartialEq)]
+pub enum PartState {
+    Pending,
+    Created,
+    Installed,
+}
+
+#[derive(Clone, CandidType, Serialize, Deserialize)]
+pub struct BootstrapPart {
+    start_node: u32,
+    end_node: u32,
+    canister_id: Option<Principal>,
+    state: PartState,
+    //failed creations and installs
+    attempts: u32,
+    error: Option<String>,
+}
+
+#[derive(Clone, CandidType, Serialize, Deserialize)]
+pub struct WasmModule {
+    //sha256 of the module
+    #[serde(with = "serde_bytes")]
+    hash: Vec<u8>,
+    #[serde(with = "serde_bytes")]
+    module: Vec<u8>,
+    uploaded_at: u64,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct WasmInfo {
+    #[serde(with = "serde_bytes")]
+    hash: Vec<u8>,
+    size: u64,
+    uploaded_at: u64,
+    //installed on new shards
+    current: bool,
+}
+
+//Upgrading every shard to one uploaded module, `batch` shards at a time
+#[derive(Clone, CandidType, Serialize, Deserialize)]
+pub struct FleetUpgrade {
+    #[serde(with = "serde_bytes")]
+    hash: Vec<u8>,
+    batch: u32,
+    shards: Vec<ShardUpgrade>,
+}
+
+#[derive(Clone, CandidType, Serialize, Deserialize)]
+pub struct ShardUpgrade {
+    canister_id: Principal,
+    state: UpgradeState,
+    attempts: u32,
+}
+
+#[derive(Clone, CandidType, Serialize, Deserialize, PartialEq)]
+pub enum UpgradeState {
+    Pending,
+    Upgraded,
+    Failed(String),
+    //could not be restarted, `install_error` is None when the new module
+    //is installed. The next call starts it again.
+    Stopped {
+        install_error: Option<String>,
+        start_error: String,
+    },
+}
+
+//Who may write through the routed write endpoints besides the owner
+#[derive(Clone, CandidType, Serialize, Deserialize, Default)]
+pub struct WritePolicy {
+    //canisters and principals allowed to write any key
+    writers: Vec<Principal>,
+    //other callers may write the keys of their own `user:{principal}` namespace
+    owned_keys: bool,
 }
 
-//Hash slot node
+//Slot range moving from a shard to a new or an existing one. The source keeps
+//serving the range until the copy is verified, then the slot map is flipped.
 #[derive(Clone, CandidType, Serialize, Deserialize)]
-pub struct CanisterNodeMap {
+pub struct SlotMigration {
+    source: Principal,
+    target: Principal,
+    start_node: u32,
+    end_node: u32,
+}
+
+//Sent by the source shard once the range is copied
+#[derive(CandidType, Deserialize)]
+pub struct MigrationReport {
+    target: Principal,
+    start_node: u32,
+    end_node: u32,
+    seq: u64,
+    entries: u64,
+}
+
+//1.Install the smart contract into canister
+//2.Send this canister id to install canister
+async fn install_canister(
+    canister_id: Principal,
+    start_node: u32,
+    end_node: u32,
+    mode: InstallMode,
+) -> Result<(), String> {
+    let canister_install_args = Encode!(&CanisterNodeMap {
+        canister_id: ic_cdk::api::id(),
+        start_node,
+        end_node
+    })
+    .unwrap();
+    slot_router::install_code(canister_id, &shard_module(), canister_install_args, mode).await
+}
+
+//Create canister
+async fn create_empty_canister() -> Result<Principal, String> {
+    slot_router::create_canister(init_canister_args()).await
+}
+
+fn init_canister_args() -> CreateCanisterArgs {
+    CreateCanisterArgs::controlled_by_self(INIT_CYCLES)
+}
+
+fn shard_module() -> Vec<u8> {
+    NODE_MAP_LISTS.with(|list| {
+        let list = list.borrow();
+        list.shard_wasm
+            .as_ref()
+            .and_then(|hash| find_wasm(&list, hash))
+            .map(|wasm| wasm.module.clone())
+            .unwrap_or_else(|| USER_WASM.to_vec())
+    })
+}
+
+fn find_wasm<'a>(list: &'a CanisterNodeMapList, hash: &[u8]) -> Option<&'a WasmModule> {
+    list.wasms
+        .as_ref()
+        .and_then(|wasms| wasms.iter().find(|wasm| wasm.hash == hash))
+}
+
+//Keep a likes module for upgrades, `hash` must be the sha256 of it
+#[update]
+#[candid::candid_method(update)]
+fn upload_wasm(module: serde_bytes::ByteBuf, hash: serde_bytes::ByteBuf) -> Result<(), String> {
+    assert_eq!(
+        NODE_MAP_LISTS.with(|allot| { allot.borrow().owner }),
+        ic_cdk::api::caller()
+    );
+    if Sha256::digest(&module).as_slice() != hash.as_slice() {
+        return Err("Error: hash is not the sha256 of the module".to_string());
+    }
+    NODE_MAP_LISTS.with(|list| {
+        let mut list = list.borrow_mut();
+        if find_wasm(&list, &hash).is_none() {
+            list.wasms.get_or_insert_with(Vec::new).push(WasmModule {
+                hash: hash.into_vec(),
+                module: module.into_vec(),
+                uploaded_at: ic_cdk::api::time(),
+            });
+        }
+    });
+    Ok(())
+}
+
+#[update]
+#[candid::candid_method(update)]
+fn delete_wasm(hash: serde_bytes::ByteBuf) -> Result<(), String> {
+    assert_eq!(
+        NODE_MAP_LISTS.with(|allot| { allot.borrow().owner }),
+        ic_cdk::api::caller()
+    );
+    NODE_MAP_LISTS.with(|list| {
+        let mut list = list.borrow_mut();
+        if list.shard_wasm.as_deref() == Some(hash.as_slice()) {
+            return Err("Error: the module is installed on new shards".to_string());
+        }
+        if find_wasm(&list, &hash).is_none() {
+            return Err("Error: unknown module".to_string());
+        }
+        if let Some(wasms) = list.wasms.as_mut() {
+            wasms.retain(|wasm| wasm.hash != hash.as_slice());
+        }
+        Ok(())
+    })
+}
+
+#[query]
+#[candid::candid_method(query)]
+fn list_wasms() -> Vec<WasmInfo> {
+    NODE_MAP_LISTS.with(|list| {
+        let list = list.borrow();
+        list.wasms
+            .iter()
+            .flatten()
+            .map(|wasm| WasmInfo {
+                hash: wasm.hash.clone(),
+                size: wasm.module.len() as u64,
+                uploaded_at: wasm.uploaded_at,
+                current: list.shard_wasm.as_ref() == Some(&wasm.hash),
+            })
+            .collect()
+    })
+}
+
+//Shards in the slot map and split targets not in it yet, merged shards
+//waiting to be deleted are left out
+fn fleet(list: &CanisterNodeMapList) -> Vec<Principal> {
+    let mut shards: Vec<Principal> = list.slot_list.iter().map(|n| n.canister_id).collect();
+    shards.extend(list.migrations.iter().flatten().map(|m| m.target));
+    shards.sort();
+    shards.dedup();
+    shards.retain(|shard| !list.retiring.iter().flatten().any(|r| r == shard));
+    shards
+}
+
+//Upgrade every shard to the uploaded module `hash`, `batch` shards at a time.
+//Calling it again with the same hash retries the shards not upgraded yet and
+//restarts stopped ones, new shards are installed with the module from the
+//start of the rollout.
+#[update]
+#[candid::candid_method(update)]
+async fn upgrade_shards(hash: serde_bytes::ByteBuf, batch: u32) -> Result<FleetUpgrade, String> {
+    assert_eq!(
+        NODE_MAP_LISTS.with(|allot| { allot.borrow().owner }),
+        ic_cdk::api::caller()
+    );
+    if batch == 0 {
+        return Err("Error: batch must be at least 1".to_string());
+    }
+    //Cleared when the rollout ends, traps included
+    let _running = match RunGuard::try_start(&UPGRADE_RUNNING) {
+        Some(guard) => guard,
+        None => return Err("Error: upgrade already running".to_string()),
+    };
+    let hash = hash.into_vec();
+    let module = NODE_MAP_LISTS.with(|list| {
+        let mut list = list.borrow_mut();
+        let module = match find_wasm(&list, &hash) {
+            Some(wasm) => wasm.module.clone(),
+            None => return Err("Error: unknown module".to_string()),
+        };
+        let mut upgrade = match list.upgrade.take() {
+            Some(upgrade) if upgrade.hash == hash => upgrade,
+            _ => FleetUpgrade {
+                hash: hash.clone(),
+                batch,
+                shards: Vec::new(),
+            },
+        };
+        upgrade.batch = batch;
+        //shards created since an earlier call
+        for canister_id in fleet(&list) {
+            if !upgrade.shards.iter().any(|s| s.canister_id == canister_id) {
+                upgrade.shards.push(ShardUpgrade {
+                    canister_id,
+                    state: UpgradeState::Pending,
+                    attempts: 0,
+                });
+            }
+        }
+        list.upgrade = Some(upgrade);
+        list.shard_wasm = Some(hash);
+        Ok(module)
+    })?;
+
+    let pending: Vec<(Principal, UpgradeState)> = NODE_MAP_LISTS.with(|list| {
+        list.borrow()
+            .upgrade
+            .iter()
+            .flat_map(|upgrade| upgrade.shards.iter())
+            .filter(|s| s.state != UpgradeState::Upgraded)
+            .map(|s| (s.canister_id, s.state.clone()))
+            .collect()
+    });
+    for shards in pending.chunks(batch as usize) {
+        let results = join_all(
+            shards
+                .iter()
+                .map(|(shard, state)| upgrade_shard(*shard, &module, state)),
+        )
+        .await;
+        NODE_MAP_LISTS.with(|list| {
+            let mut list = list.borrow_mut();
+            let upgrade = list.upgrade.as_mut().expect("upgrade recorded above");
+            for ((shard, _), state) in shards.iter().zip(results) {
+                if let Some(s) = upgrade.shards.iter_mut().find(|s| s.canister_id == *shard) {
+                    s.attempts += 1;
+                    s.state = state;
+                }
+            }
+        });
+    }
+
+    Ok(upgrade_status().expect("upgrade recorded above"))
+}
+
+//Stop, upgrade and restart one shard
+async fn upgrade_shard(
     canister_id: Principal,
+    module: &[u8],
+    state: &UpgradeState,
+) -> UpgradeState {
+    //A shard left stopped by an earlier attempt is started first
+    if let UpgradeState::Stopped { install_error, .. } = state {
+        if let Err(start_error) = slot_router::start_canister(canister_id).await {
+            return UpgradeState::Stopped {
+                install_error: install_error.clone(),
+                start_error,
+            };
+        }
+        if install_error.is_none() {
+            return UpgradeState::Upgraded;
+        }
+    }
+
+    //Same argument install_canister gives, likes keeps its state on upgrade
+    let (start_node, end_node) = match shard_range(canister_id) {
+        Some(range) => range,
+        None => return UpgradeState::Failed(format!("Error: {} owns no slot range", canister_id)),
+    };
+    let arg = Encode!(&CanisterNodeMap {
+        canister_id: ic_cdk::api::id(),
+        start_node,
+        end_node
+    })
+    .unwrap();
+
+    if let Err(e) = slot_router::stop_canister(canister_id).await {
+        return UpgradeState::Failed(e);
+    }
+    let installed = slot_router::install_code(canister_id, module, arg, InstallMode::Upgrade).await;
+    //restart the shard even when the upgrade failed
+    match (installed, slot_router::start_canister(canister_id).await) {
+        (Ok(()), Ok(())) => UpgradeState::Upgraded,
+        (Err(e), Ok(())) => UpgradeState::Failed(e),
+        (installed, Err(start_error)) => UpgradeState::Stopped {
+            install_error: installed.err(),
+            start_error,
+        },
+    }
+}
+
+#[query]
+#[candid::candid_method(query)]
+fn upgrade_status() -> Option<FleetUpgrade> {
+    NODE_MAP_LISTS.with(|list| list.borrow().upgrade.clone())
+}
+
+//1.Create canisters
+//2.Map node to hash slot
+//3.Saving mapping table
+//The job is kept in the state, a call that fails part way is picked up by
+//resume_bootstrap or by calling again with the same `parts`
+#[update]
+#[candid::candid_method(update)]
+async fn batch_create_canisters(parts: u32) -> Result<Vec<CanisterNodeMap>, String> {
+    assert_eq!(
+        NODE_MAP_LISTS.with(|allot| { allot.borrow().owner }),
+        ic_cdk::api::caller()
+    );
+    if parts == 0 || parts > SLOT_SIZE {
+        return Err(format!("Error: parts must be in 1..={}", SLOT_SIZE));
+    }
+
+    let job_parts = NODE_MAP_LISTS.with(|list| {
+        let mut list = list.borrow_mut();
+        if list.bootstrap.is_none() && !list.slot_list.is_empty() {
+            return Err("Error: slot map already published".to_string());
+        }
+        let job = list.bootstrap.get_or_insert_with(|| BootstrapJob {
+            parts: split_slots(parts)
+                .into_iter()
+                .map(|(start_node, end_node)| BootstrapPart {
+                    start_node,
+                    end_node,
+                    canister_id: None,
+                    state: PartState::Pending,
+                    attempts: 0,
+                    error: None,
+                })
+                .collect(),
+            published: false,
+        });
+        Ok(job.parts.len() as u32)
+    })?;
+    if job_parts != parts {
+        return Err(format!(
+            "Error: a bootstrap of {} parts was already started",
+            job_parts
+        ));
+    }
+    run_bootstrap().await
+}
+
+#[update]
+#[candid::candid_method(update)]
+async fn resume_bootstrap() -> Result<Vec<CanisterNodeMap>, String> {
+    assert_eq!(
+        NODE_MAP_LISTS.with(|allot| { allot.borrow().owner }),
+        ic_cdk::api::caller()
+    );
+    if NODE_MAP_LISTS.with(|list| list.borrow().bootstrap.is_none()) {
+        return Err("Error: no bootstrap started".to_string());
+    }
+    run_bootstrap().await
+}
+
+#[query]
+#[candid::candid_method(query)]
+fn bootstrap_status() -> Option<BootstrapJob> {
+    NODE_MAP_LISTS.with(|list| list.borrow().bootstrap.clone())
+}
+
+fn update_part(i: usize, update: impl FnOnce(&mut BootstrapPart)) {
+    NODE_MAP_LISTS.with(|list| {
+        if let Some(part) = list
+            .borrow_mut()
+            .bootstrap
+            .as_mut()
+            .and_then(|job| job.parts.get_mut(i))
+        {
+            update(part);
+        }
+    });
+}
+
+//Create and install every part not installed yet, then publish the slot map
+//once all of them are
+async fn run_bootstrap() -> Result<Vec<CanisterNodeMap>, String> {
+    //Cleared when the run ends, traps included
+    let _running = match RunGuard::try_start(&BOOTSTRAP_RUNNING) {
+        Some(guard) => guard,
+        None => return Err("Error: bootstrap already running".to_string()),
+    };
+    let parts = NODE_MAP_LISTS.with(|list| {
+        list.borrow()
+            .bootstrap
+            .as_ref()
+            .map(|job| job.parts.clone())
+            .unwrap_or_default()
+    });
+
+    for (i, part) in parts.into_iter().enumerate() {
+        if part.state == PartState::Installed {
+            continue;
+        }
+        let canister_id = match part.canister_id {
+            Some(canister_id) => canister_id,
+            None => match create_empty_canister().await {
+                Ok(canister_id) => {
+                    update_part(i, |p| {
+                        p.canister_id = Some(canister_id);
+                        p.state = PartState::Created;
+                        p.error = None;
+                    });
+                    canister_id
+                }
+                Err(e) => {
+                    update_part(i, |p| {
+                        p.attempts += 1;
+                        p.error = Some(e);
+                    });
+                    continue;
+                }
+            },
+        };
+
+        //An earlier install may have gone through without its reply, nothing
+        //is routed to the canister yet so reinstalling it is safe
+        let mode = match part.attempts {
+            0 => InstallMode::Install,
+            _ => InstallMode::Reinstall,
+        };
+        let result = install_canister(canister_id, part.start_node, part.end_node, mode).await;
+        update_part(i, |p| {
+            p.attempts += 1;
+            match result {
+                Ok(()) => {
+                    p.state = PartState::Installed;
+                    p.error = None;
+                }
+                Err(e) => p.error = Some(e),
+            }
+        });
+    }
+
+    publish_bootstrap()
+}
+
+//The slot map is only published with every range on an installed canister
+fn publish_bootstrap() -> Result<Vec<CanisterNodeMap>, String> {
+    NODE_MAP_LISTS.with(|list| {
+        let mut list = list.borrow_mut();
+        let job = match list.bootstrap.as_mut() {
+            Some(job) => job,
+            None => return Err("Error: no bootstrap started".to_string()),
+        };
+        if job.published {
+            return Ok(list.slot_list.to_vec());
+        }
+        let missing = job
+            .parts
+            .iter()
+            .filter(|p| p.state != PartState::Installed)
+            .count();
+        if missing > 0 {
+            return Err(format!(
+                "Error: {} of {} ranges not installed, see bootstrap_status and resume_bootstrap",
+                missing,
+                job.parts.len()
+            ));
+        }
+
+        let mut slot_list = SlotMap::new();
+        for part in job.parts.iter() {
+            slot_list.insert(CanisterNodeMap {
+                canister_id: part.canister_id.expect("installed part has a canister"),
+                start_node: part.start_node,
+                end_node: part.end_node,
+            })?;
+        }
+        job.published = true;
+        list.slot_list = slot_list;
+        Ok(list.slot_list.to_vec())
+    })
+}
+
+//Oldest pending migration of `source`
+fn find_migration(source: Principal) -> Option<SlotMigration> {
+    NODE_MAP_LISTS.with(|list| {
+        list.borrow()
+            .migrations
+            .iter()
+            .flatten()
+            .find(|m| m.source == source)
+            .cloned()
+    })
+}
+
+fn find_migration_to(source: Principal, target: Principal) -> Option<SlotMigration> {
+    NODE_MAP_LISTS.with(|list| {
+        list.borrow()
+            .migrations
+            .iter()
+            .flatten()
+            .find(|m| m.source == source && m.target == target)
+            .cloned()
+    })
+}
+
+fn record_migration(m: SlotMigration) {
+    NODE_MAP_LISTS.with(|list_ref| {
+        list_ref
+            .borrow_mut()
+            .migrations
+            .get_or_insert_with(Vec::new)
+            .push(m);
+    });
+}
+
+//Range of a shard in the slot map, or the split it is the target of
+fn shard_range(canister_id: Principal) -> Option<(u32, u32)> {
+    if let Some(range) = largest_range(canister_id) {
+        return Some((range.start_node, range.end_node));
+    }
+    NODE_MAP_LISTS.with(|list| {
+        list.borrow()
+            .migrations
+            .iter()
+            .flatten()
+            .find(|m| m.target == canister_id)
+            .map(|m| (m.start_node, m.end_node))
+    })
+}
+
+//Largest slot range of a shard, the one splits take slots from
+fn largest_range(canister_id: Principal) -> Option<CanisterNodeMap> {
+    NODE_MAP_LISTS.with(|list| {
+        list.borrow()
+            .slot_list
+            .ranges_of(&canister_id)
+            .max_by_key(|elem| elem.end_node - elem.start_node)
+            .cloned()
+    })
+}
+
+async fn fetch_slot_loads(
+    shard: Principal,
+    start_node: u32,
+    end_node: u32,
+) -> Result<Vec<SlotLoad>, String> {
+    match ic_cdk::call::<_, (Result<Vec<SlotLoad>, String>,)>(
+        shard,
+        "slot_loads",
+        (start_node, end_node),
+    )
+    .await
+    {
+        Ok((result,)) => result,
+        Err((code, msg)) => Err(format!(
+            "An error happened during the call slot_loads: {}: {}",
+            code as u8, msg
+        )),
+    }
+}
+
+async fn fetch_memory_size(shard: Principal) -> Result<u64, String> {
+    match ic_cdk::call::<_, (u64,)>(shard, "memory_size", ()).await {
+        Ok((size,)) => Ok(size),
+        Err((code, msg)) => Err(format!(
+            "An error happened during the call memory_size: {}: {}",
+            code as u8, msg
+        )),
+    }
+}
+
+//Create and install the shard taking over start_node..=end_node of `source`
+//and record the migration. The slot map only changes once the data is
+//copied, see migration_copied.
+async fn create_split_target(
+    source: Principal,
     start_node: u32,
     end_node: u32,
+) -> Result<SlotMigration, String> {
+    let create_canister_id = create_empty_canister().await?;
+    install_canister(
+        create_canister_id,
+        start_node,
+        end_node,
+        InstallMode::Install,
+    )
+    .await?;
+    let m = SlotMigration {
+        source,
+        target: create_canister_id,
+        start_node,
+        end_node,
+    };
+    record_migration(m.clone());
+    Ok(m)
+}
+
+//Tell the source shard to start copying a recorded migration
+async fn start_migration(m: &SlotMigration) {
+    let args = CanisterNodeMap {
+        canister_id: m.target,
+        start_node: m.start_node,
+        end_node: m.end_node,
+    };
+    if let Err(e) = callback_new_canister_args(m.source, args).await {
+        print(e);
+    }
+}
+
+//Create the canister taking over half of the full canister's bytes
+async fn insert_single_canister(prev_canister_id: Principal) -> Result<CanisterNodeMap, String> {
+    //A split already under way is handed out again
+    if let Some(m) = find_migration(prev_canister_id) {
+        return Ok(CanisterNodeMap {
+            canister_id: m.target,
+            start_node: m.start_node,
+            end_node: m.end_node,
+        });
+    }
+
+    let prev = match largest_range(prev_canister_id) {
+        Some(prev) if prev.start_node < prev.end_node => prev,
+        Some(_) => return Err("Slot range can not be split!".to_string()),
+        None => return Err("Canister not in slot map!".to_string()),
+    };
+
+    //Without loads the range is cut at its midpoint
+    let loads = fetch_slot_loads(prev_canister_id, prev.start_node, prev.end_node)
+        .await
+        .unwrap_or_else(|e| {
+            print(e);
+            vec![]
+        });
+    let ranges = balanced_ranges(prev.start_node, prev.end_node, &loads, 2);
+    let (new_start_node, new_end_node) = ranges[1];
+
+    let m = create_split_target(prev_canister_id, new_start_node, new_end_node).await?;
+    Ok(CanisterNodeMap {
+        canister_id: m.target,
+        start_node: m.start_node,
+        end_node: m.end_node,
+    })
+}
+
+//Split the largest range of a shard into `parts` ranges holding about the
+//same bytes, every range but the lowest moves to a new shard
+#[update]
+#[candid::candid_method(update)]
+async fn split_shard(source: Principal, parts: u32) -> Result<Vec<SlotMigration>, String> {
+    assert_eq!(
+        NODE_MAP_LISTS.with(|allot| { allot.borrow().owner }),
+        ic_cdk::api::caller()
+    );
+    if parts < 2 {
+        return Err("Error: a split needs at least 2 parts".to_string());
+    }
+    if find_migration(source).is_some() {
+        return Err(format!("Error: {} is already migrating", source));
+    }
+    let range =
+        largest_range(source).ok_or_else(|| format!("Error: {} is not in the slot map", source))?;
+
+    let loads = fetch_slot_loads(source, range.start_node, range.end_node).await?;
+    let ranges = balanced_ranges(range.start_node, range.end_node, &loads, parts);
+
+    //Ranges leave from the top, each one ends where the source range does
+    //by the time it is flipped. A failed install leaves the rest in place.
+    let mut started = vec![];
+    for (start_node, end_node) in ranges.into_iter().skip(1).rev() {
+        match create_split_target(source, start_node, end_node).await {
+            Ok(m) => {
+                start_migration(&m).await;
+                started.push(m);
+            }
+            Err(e) => {
+                print(e);
+                break;
+            }
+        }
+    }
+    if started.is_empty() {
+        return Err(format!("Error: no shard created to split {}", source));
+    }
+    Ok(started)
+}
+
+//Move the top of the largest range of a shard to an existing shard holding
+//less data, so both end up with about the same bytes
+#[update]
+#[candid::candid_method(update)]
+async fn move_slots(source: Principal, target: Principal) -> Result<SlotMigration, String> {
+    assert_eq!(
+        NODE_MAP_LISTS.with(|allot| { allot.borrow().owner }),
+        ic_cdk::api::caller()
+    );
+    if source == target || !is_exisr(target) {
+        return Err(format!("Error: {} is not another shard", target));
+    }
+    if find_migration(source).is_some() {
+        return Err(format!("Error: {} is already migrating", source));
+    }
+    let range = match largest_range(source) {
+        Some(range) if range.start_node < range.end_node => range,
+        Some(_) => return Err("Slot range can not be split!".to_string()),
+        None => return Err(format!("Error: {} is not in the slot map", source)),
+    };
+
+    let source_bytes = fetch_memory_size(source).await?;
+    let target_bytes = fetch_memory_size(target).await?;
+    if source_bytes <= target_bytes {
+        return Err(format!(
+            "Error: {} holds no more data than {}",
+            source, target
+        ));
+    }
+    let loads = fetch_slot_loads(source, range.start_node, range.end_node).await?;
+    let cut = upper_cut(
+        range.start_node,
+        range.end_node,
+        &loads,
+        (source_bytes - target_bytes) / 2,
+    );
+
+    //Checked again, the calls above gave other messages a chance to start one
+    if find_migration(source).is_some() {
+        return Err(format!("Error: {} is already migrating", source));
+    }
+    let m = SlotMigration {
+        source,
+        target,
+        start_node: cut,
+        end_node: range.end_node,
+    };
+    record_migration(m.clone());
+    start_migration(&m).await;
+    Ok(m)
+}
+
+//Hand the migrated range to the target in the slot map
+fn flip_slots(m: &SlotMigration) -> Result<(), String> {
+    NODE_MAP_LISTS.with(|list_ref| {
+        let mut list = list_ref.borrow_mut();
+
+        //updata node, a merge moves the whole range
+        let elem = list
+            .slot_list
+            .find(m.start_node)
+            .filter(|elem| {
+                elem.canister_id == m.source
+                    && elem.start_node <= m.start_node
+                    && elem.end_node == m.end_node
+            })
+            .cloned()
+            .ok_or_else(|| "Error: source range changed during migration".to_string())?;
+        list.slot_list.remove(elem.start_node);
+        if elem.start_node < m.start_node {
+            list.slot_list.insert(CanisterNodeMap {
+                end_node: m.start_node - 1,
+                ..elem
+            })?;
+        }
+        list.slot_list.insert(CanisterNodeMap {
+            canister_id: m.target,
+            start_node: m.start_node,
+            end_node: m.end_node,
+        })?;
+        if let Some(migrations) = list.migrations.as_mut() {
+            migrations
+                .retain(|pending| !(pending.source == m.source && pending.target == m.target));
+        }
+        //Ranges a pending split of the target expects are left as they are
+        let splitting = list
+            .migrations
+            .iter()
+            .flatten()
+            .any(|pending| pending.source == m.target);
+        if !splitting {
+            list.slot_list.coalesce(&m.target);
+        }
+        Ok(())
+    })
+}
+
+//1.Check the target applied everything the source sent
+//2.Flip the range to the target, the source deletes its copy afterwards
+#[update]
+#[candid::candid_method(update)]
+async fn migration_copied(report: MigrationReport) -> Result<(), String> {
+    let source = ic_cdk::api::caller();
+    let m = match find_migration_to(source, report.target) {
+        Some(m) if m.start_node == report.start_node && m.end_node == report.end_node => m,
+        //Already flipped, the reply to an earlier call did not reach the source
+        None if owns_range(report.target, report.start_node, report.end_node) => return Ok(()),
+        _ => return Err(format!("Error: no migration from {}", source)),
+    };
+
+    match ic_cdk::call::<_, (Result<(), String>,)>(
+        m.target,
+        "confirm_migration",
+        (source, report.seq, report.entries),
+    )
+    .await
+    {
+        Ok((Ok(()),)) => {}
+        Ok((Err(e),)) => return Err(e),
+        Err((code, msg)) => {
+            return Err(format!(
+                "An error happened during the call confirm_migration: {}: {}",
+                code as u8, msg
+            ))
+        }
+    }
+    flip_slots(&m)?;
+
+    //The merged shard is stopped once this reply released its call
+    if is_retiring(source) && !is_exisr(source) {
+        ic_cdk::spawn(async move {
+            if let Err(e) = retire(source).await {
+                print(e);
+            }
+        });
+    }
+    Ok(())
+}
+
+fn owns_range(canister_id: Principal, start_node: u32, end_node: u32) -> bool {
+    NODE_MAP_LISTS.with(|list| {
+        list.borrow()
+            .slot_list
+            .find(start_node)
+            .is_some_and(|elem| elem.canister_id == canister_id && elem.end_node >= end_node)
+    })
+}
+
+fn is_retiring(canister_id: Principal) -> bool {
+    NODE_MAP_LISTS.with(|list| {
+        list.borrow()
+            .retiring
+            .iter()
+            .flatten()
+            .any(|p| *p == canister_id)
+    })
+}
+
+//Merge the only range of `source` into the adjacent range of `target`, then
+//delete `source` and take its cycles back
+#[update]
+#[candid::candid_method(update)]
+async fn merge_shards(source: Principal, target: Principal) -> Result<SlotMigration, String> {
+    assert_eq!(
+        NODE_MAP_LISTS.with(|allot| { allot.borrow().owner }),
+        ic_cdk::api::caller()
+    );
+    let range = check_merge(source, target)?;
+
+    let source_bytes = fetch_memory_size(source).await?;
+    let target_bytes = fetch_memory_size(target).await?;
+    if source_bytes.saturating_add(target_bytes) > MAX_MERGED_BYTES {
+        return Err(format!(
+            "Error: {} and {} hold too much data to merge",
+            source, target
+        ));
+    }
+
+    //Checked again, the calls above gave other messages a chance to start one
+    let range = check_merge(source, target).and_then(|again| {
+        if again == range {
+            Ok(again)
+        } else {
+            Err(format!("Error: slot range of {} changed", source))
+        }
+    })?;
+    let m = SlotMigration {
+        source,
+        target,
+        start_node: range.start_node,
+        end_node: range.end_node,
+    };
+    record_migration(m.clone());
+    NODE_MAP_LISTS.with(|list| {
+        list.borrow_mut()
+            .retiring
+            .get_or_insert_with(Vec::new)
+            .push(source)
+    });
+    start_migration(&m).await;
+    Ok(m)
+}
+
+//Range of `source` that can move to `target`: its only range, next to one of
+//`target`, with neither shard migrating
+fn check_merge(source: Principal, target: Principal) -> Result<CanisterNodeMap, String> {
+    if source == target || !is_exisr(target) {
+        return Err(format!("Error: {} is not another shard", target));
+    }
+    let busy = NODE_MAP_LISTS.with(|list| {
+        list.borrow().migrations.iter().flatten().any(|m| {
+            [m.source, m.target].contains(&source) || [m.source, m.target].contains(&target)
+        })
+    });
+    if busy {
+        return Err(format!(
+            "Error: {} or {} is already migrating",
+            source, target
+        ));
+    }
+    NODE_MAP_LISTS.with(|list| {
+        let list = list.borrow();
+        let ranges: Vec<&CanisterNodeMap> = list.slot_list.ranges_of(&source).collect();
+        let range = match ranges.as_slice() {
+            [range] => (*range).clone(),
+            [] => return Err(format!("Error: {} is not in the slot map", source)),
+            _ => return Err(format!("Error: {} owns more than one range", source)),
+        };
+        let below = range
+            .start_node
+            .checked_sub(1)
+            .and_then(|slot| list.slot_list.find(slot));
+        let above = range
+            .end_node
+            .checked_add(1)
+            .and_then(|slot| list.slot_list.find(slot));
+        let adjacent = [below, above]
+            .iter()
+            .flatten()
+            .any(|elem| elem.canister_id == target);
+        if !adjacent {
+            return Err(format!("Error: {} is not next to {}", source, target));
+        }
+        Ok(range)
+    })
+}
+
+//Take the cycles of a shard that owns no slots any more, then stop and
+//delete it
+async fn retire(canister_id: Principal) -> Result<u64, String> {
+    if is_exisr(canister_id) {
+        return Err(format!("Error: {} still owns slots", canister_id));
+    }
+    //A stopped canister can not send its cycles, an earlier attempt may
+    //have stopped it
+    let status = slot_router::canister_status(canister_id).await?;
+    if !matches!(status.status, CanisterStatusType::Running) {
+        slot_router::start_canister(canister_id).await?;
+    }
+
+    let returned =
+        match ic_cdk::call::<_, (Result<u64, String>,)>(canister_id, "return_cycles", ()).await {
+            Ok((result,)) => result?,
+            Err((code, msg)) => {
+                return Err(format!(
+                    "An error happened during the call return_cycles: {}: {}",
+                    code as u8, msg
+                ))
+            }
+        };
+    slot_router::stop_canister(canister_id).await?;
+    slot_router::delete_canister(canister_id).await?;
+
+    NODE_MAP_LISTS.with(|list| {
+        if let Some(retiring) = list.borrow_mut().retiring.as_mut() {
+            retiring.retain(|p| *p != canister_id);
+        }
+    });
+    //The shard keeps a reserve to pay for its reply, the rest of what it
+    //held when checked is lost with it
+    let leftover = if status.cycles > returned {
+        status.cycles - Nat::from(returned)
+    } else {
+        Nat::from(0u64)
+    };
+    print(format!(
+        "Shard {} deleted, {} cycles returned, {} cycles left behind",
+        canister_id, returned, leftover
+    ));
+    Ok(returned)
+}
+
+//Retry deleting a merged shard, returns the cycles it gave back
+#[update]
+#[candid::candid_method(update)]
+async fn retire_shard(canister_id: Principal) -> Result<u64, String> {
+    assert_eq!(
+        NODE_MAP_LISTS.with(|allot| { allot.borrow().owner }),
+        ic_cdk::api::caller()
+    );
+    if !is_retiring(canister_id) {
+        return Err(format!("Error: {} is not being retired", canister_id));
+    }
+    retire(canister_id).await
+}
+
+#[query]
+#[candid::candid_method(query)]
+fn retiring_shards() -> Vec<Principal> {
+    NODE_MAP_LISTS.with(|list| list.borrow().retiring.clone().unwrap_or_default())
+}
+
+//Called by the target of a split to check who may send it data: the
+//pending migration from the `source` shard to the caller
+#[query]
+#[candid::candid_method(query)]
+fn verify_migration(source: Principal) -> Option<SlotMigration> {
+    let target = ic_cdk::api::caller();
+    find_migration_to(source, target).filter(|m| is_exisr(m.source))
 }
 
-#[derive(CandidType, Deserialize)]
-enum InstallMode {
-    #[serde(rename = "install")]
-    Install,
-    #[serde(rename = "reinstall")]
-    Reinstall,
-    #[serde(rename = "upgrade")]
-    Upgrade,
+#[query]
+#[candid::candid_method(query)]
+fn pending_migrations() -> Vec<SlotMigration> {
+    NODE_MAP_LISTS.with(|list| list.borrow().migrations.clone().unwrap_or_default())
 }
 
-#[derive(CandidType, Clone, Deserialize, Debug)]
-pub struct CanisterIdRecord {
-    pub canister_id: Principal,
+//Get the currently owned User Canister and Hash slot mapping table
+#[query]
+#[candid::candid_method(query)]
+fn allot_canister_list() -> Vec<CanisterNodeMap> {
+    NODE_MAP_LISTS.with(|list| list.borrow().slot_list.to_vec())
 }
 
-#[derive(CandidType, Debug, Clone, Deserialize)]
-pub struct CreateCanisterSettings {
-    pub controllers: Option<Vec<Principal>>,
-    pub compute_allocation: Option<Nat>,
-    pub memory_allocation: Option<Nat>,
-    pub freezing_threshold: Option<Nat>,
+//Shard owning `key`, an error when the slot map does not cover its slot
+fn lookup_canister(key: &str) -> Result<Principal, String> {
+    NODE_MAP_LISTS.with(|list| list.borrow().slot_list.lookup(key))
 }
 
-#[derive(CandidType, Debug, Clone, Deserialize)]
-pub struct CreateCanisterArgs {
-    pub cycles: u64,
-    pub settings: CreateCanisterSettings,
+// Get the Canister ID of the node
+#[query]
+#[candid::candid_method(query)]
+fn get_correlation_canister(key: String) -> Result<Principal, String> {
+    lookup_canister(&key)
 }
 
-#[derive(CandidType, Deserialize)]
-struct CanisterInstall {
-    mode: InstallMode,
-    canister_id: Principal,
-    #[serde(with = "serde_bytes")]
-    wasm_module: Vec<u8>,
-    #[serde(with = "serde_bytes")]
-    arg: Vec<u8>,
+#[query]
+#[candid::candid_method(query)]
+fn validate_slot_map() -> SlotMapReport {
+    NODE_MAP_LISTS.with(|list| check_slot_map(&list.borrow().slot_list.to_vec()))
 }
 
-//1.Install the smart contract into canister
-//2.Send this canister id to install canister
-async fn install_canister(canister_id: &Principal, canister_install_args: Vec<u8>) -> bool {
-    let install_config: CanisterInstall = CanisterInstall {
-        mode: InstallMode::Install,
-        canister_id: canister_id.clone(),
-        wasm_module: USER_WASM.to_vec(),
-        arg: canister_install_args,
-    };
+#[update]
+#[candid::candid_method(update)]
+fn repair_slot_map() -> Vec<CanisterNodeMap> {
+    assert_eq!(
+        NODE_MAP_LISTS.with(|allot| { allot.borrow().owner }),
+        ic_cdk::api::caller()
+    );
 
-    match ic_cdk::api::call::call(
-        Principal::management_canister(),
-        "install_code",
-        (install_config,),
-    )
-    .await
-    {
-        Ok(x) => x,
-        Err((code, msg)) => {
-            print(format!(
-                "An error happened during the call: {}: {}",
-                code as u8, msg
-            ));
-            return false;
-        }
-    };
-    true
+    NODE_MAP_LISTS.with(|list| {
+        let mut list = list.borrow_mut();
+        list.slot_list = SlotMap::from(repaired_slot_map(&list.slot_list.to_vec()));
+        list.slot_list.to_vec()
+    })
 }
 
-//Create canister
-async fn create_empty_canister(canister_create_args: CreateCanisterArgs) -> Principal {
-    #[derive(CandidType)]
-    struct In {
-        settings: Option<CreateCanisterSettings>,
-    }
+#[query]
+#[candid::candid_method(query)]
+fn verify_canister(pid: Principal) -> bool {
+    is_exisr(pid)
+}
 
-    let in_arg = In {
-        settings: Some(canister_create_args.settings),
-    };
+//`user:{principal}` or a key below it such as `user:{principal}:likes`
+fn owns_key(caller: &Principal, key: &str) -> bool {
+    let namespace = format!("user:{}", caller);
+    key == namespace || key.starts_with(&format!("{}:", namespace))
+}
 
-    let (create_result,): (CanisterIdRecord,) = match ic_cdk::api::call::call_with_payment(
-        Principal::management_canister(),
-        "create_canister",
-        (in_arg,),
-        canister_create_args.cycles,
-    )
-    .await
-    {
-        Ok(x) => x,
-        Err((code, msg)) => {
-            print(format!(
-                "An error happened during the call: {}: {}",
-                code as u8, msg
-            ));
-            (CanisterIdRecord {
-                canister_id: Principal::anonymous(),
-            },)
+fn may_write(caller: Principal, key: &str) -> bool {
+    NODE_MAP_LISTS.with(|list| {
+        let list = list.borrow();
+        if caller == list.owner {
+            return true;
         }
-    };
-    create_result.canister_id
+        match &list.write_policy {
+            Some(policy) => {
+                policy.writers.contains(&caller)
+                    || (policy.owned_keys
+                        && caller != Principal::anonymous()
+                        && owns_key(&caller, key))
+            }
+            None => false,
+        }
+    })
 }
 
-fn init_canister_args() -> CreateCanisterArgs {
-    let this_canister_id = ic_cdk::api::id();
-
-    CreateCanisterArgs {
-        cycles: INIT_CYCLES,
-        settings: CreateCanisterSettings {
-            controllers: Some(vec![this_canister_id]),
-            compute_allocation: None,
-            memory_allocation: None,
-            freezing_threshold: None,
-        },
+fn check_write(key: &str) -> Result<(), RouteError> {
+    if may_write(ic_cdk::api::caller(), key) {
+        Ok(())
+    } else {
+        Err(RouteError::Unauthorized)
     }
 }
 
-// #[update]
-// async fn fix() -> bool {
-//     let nodes = NODE_MAP_LISTS.with(|list| list.borrow().slot_list.clone());
-//     for elem in nodes {
-//         let send_args: CanisterNodeMap = CanisterNodeMap {
-//             canister_id: elem.canister_id,
-//             start_node: 0,
-//             end_node: SLOT_SIZE - 1,
-//         };
-//         let canister_install_args = Encode!(&send_args).unwrap();
-
-//         let install_config: CanisterInstall = CanisterInstall {
-//             mode: InstallMode::Upgrade,
-//             canister_id: elem.canister_id,
-//             wasm_module: USER_WASM.to_vec(),
-//             arg: canister_install_args,
-//         };
-
-//         match ic_cdk::api::call::call(
-//             Principal::management_canister(),
-//             "install_code",
-//             (install_config,),
-//         )
-//         .await
-//         {
-//             Ok(x) => x,
-//             Err((code, msg)) => {
-//                 print(format!(
-//                     "An error happened during the call: {}: {}",
-//                     code as u8, msg
-//                 ));
-//                 return false;
-//             }
-//         };
-//     }
-//     true
-// }
-
-//1.Create canisters
-//2.Map node to hash slot
-//3.Saving mapping table
 #[update]
 #[candid::candid_method(update)]
-async fn batch_create_canisters(parts: u32) -> Vec<CanisterNodeMap> {
-    let caller = ic_cdk::api::caller();
-    assert_eq!(NODE_MAP_LISTS.with(|list| list.borrow().slot_list.len()), 0);
+fn set_writers(writers: Vec<Principal>, allowed: bool) {
     assert_eq!(
         NODE_MAP_LISTS.with(|allot| { allot.borrow().owner }),
-        caller
+        ic_cdk::api::caller()
     );
 
-    let part_size = SLOT_SIZE / parts;
-
-    let create_args = init_canister_args();
+    NODE_MAP_LISTS.with(|list| {
+        let mut list = list.borrow_mut();
+        let policy = list.write_policy.get_or_insert_with(WritePolicy::default);
+        for writer in writers {
+            policy.writers.retain(|w| *w != writer);
+            if allowed {
+                policy.writers.push(writer);
+            }
+        }
+    });
+}
 
-    let controllers_id = ic_cdk::api::id();
+#[update]
+#[candid::candid_method(update)]
+fn set_owned_keys(enabled: bool) {
+    assert_eq!(
+        NODE_MAP_LISTS.with(|allot| { allot.borrow().owner }),
+        ic_cdk::api::caller()
+    );
 
-    for i in 0..parts {
-        let create_canister_id = create_empty_canister(create_args.clone()).await;
+    NODE_MAP_LISTS.with(|list| {
+        list.borrow_mut()
+            .write_policy
+            .get_or_insert_with(WritePolicy::default)
+            .owned_keys = enabled;
+    });
+}
 
-        let start_node = i * part_size;
-        let end_node = start_node + (part_size - 1);
+#[query]
+#[candid::candid_method(query)]
+fn get_write_policy() -> WritePolicy {
+    NODE_MAP_LISTS.with(|list| list.borrow().write_policy.clone().unwrap_or_default())
+}
 
-        let send_args: CanisterNodeMap = CanisterNodeMap {
-            canister_id: controllers_id,
-            start_node,
-            end_node,
-        };
-        let canister_install_args = Encode!(&send_args).unwrap();
+#[derive(Clone, CandidType, Deserialize)]
+pub enum RouteError {
+    //the slot map has no canister for the key's slot
+    NoShard(u32),
+    //the call failed or the shard trapped, with the reject code and message
+    Rejected(String),
+    //the key's slot range is moving to another shard, retry later
+    Migrating,
+    //the shard does not accept writes from this canister
+    Unauthorized,
+    //the shard ran the call and refused it, e.g. a counter that is not a number
+    Refused(String),
+}
 
-        if install_canister(&create_canister_id, canister_install_args).await {
-            NODE_MAP_LISTS.with(|slot_list| {
-                let mut list = slot_list.borrow_mut();
-                list.slot_list.push(CanisterNodeMap {
-                    canister_id: create_canister_id,
-                    start_node,
-                    end_node,
-                });
-            });
-        }
+fn route_error(method: &str, code: RejectionCode, msg: String) -> RouteError {
+    if msg.contains(ERR_SHARD_MIGRATING) {
+        RouteError::Migrating
+    } else if msg.contains(ERR_SHARD_UNAUTHORIZED) {
+        RouteError::Unauthorized
+    } else {
+        RouteError::Rejected(format!("{}: {}: {}", method, code as u8, msg))
     }
+}
 
-    NODE_MAP_LISTS.with(|list| list.borrow().slot_list.clone())
+//Errors a shard answers with instead of trapping
+fn shard_error(e: String) -> RouteError {
+    if e == ERR_SHARD_MIGRATING {
+        RouteError::Migrating
+    } else {
+        RouteError::Refused(e)
+    }
 }
 
-//insert new canister and map id to hash slot
-async fn insert_single_canister(prev_canister_id: Principal) -> Result<CanisterNodeMap, String> {
-    let create_args = init_canister_args();
-    let controllers_id = ic_cdk::api::id();
+async fn try_call<T, R>(target_id: Principal, method: &str, args: T) -> Result<R, RouteError>
+where
+    T: ArgumentEncoder,
+    R: for<'a> ArgumentDecoder<'a>,
+{
+    ic_cdk::api::call::call(target_id, method, args)
+        .await
+        .map_err(|(code, msg)| route_error(method, code, msg))
+}
 
-    let create_canister_id = create_empty_canister(create_args.clone()).await;
+//Forward a call to the shard owning `key` and hand back its answer or why
+//there is none
+async fn try_route<T, R>(key: &str, method: &str, args: T) -> Result<R, RouteError>
+where
+    T: ArgumentEncoder,
+    R: for<'a> ArgumentDecoder<'a>,
+{
+    let target_id = lookup_canister(key).map_err(|_| RouteError::NoShard(key_slot(key)))?;
+    try_call(target_id, method, args).await
+}
 
-    let mut new_start_node: u32 = 0;
-    let mut new_end_node: u32 = 0;
-    let mut mid_node: u32 = 0;
+#[update]
+#[candid::candid_method(update)]
+async fn hset(key: String, field: String, value: Vec<u8>) -> Result<bool, RouteError> {
+    check_write(&key)?;
+    let (stored,): (bool,) = try_route(&key, "hset", (&key, field, value)).await?;
+    Ok(stored)
+}
 
-    NODE_MAP_LISTS.with(|slot_list| {
-        let list = slot_list.borrow();
-        let vecter_iterator = list.slot_list.iter();
-        for elem in vecter_iterator {
-            if elem.canister_id == prev_canister_id {
-                mid_node = elem.start_node.clone()
-                    + ((elem.end_node.clone() + 1 - elem.start_node.clone()) >> 1);
+#[update]
+#[candid::candid_method(update)]
+async fn hdel(key: String, field: String) -> Result<bool, RouteError> {
+    check_write(&key)?;
+    let (removed,): (bool,) = try_route(&key, "hdel", (&key, field)).await?;
+    Ok(removed)
+}
 
-                new_start_node = mid_node.clone() + 1;
-                new_end_node = elem.end_node;
-            }
+//Indexes of batch entries grouped by the shard owning their key, entries
+//without a shard are left out
+fn group_by_shard<'a>(keys: impl Iterator<Item = &'a String>) -> BTreeMap<Principal, Vec<usize>> {
+    let mut groups: BTreeMap<Principal, Vec<usize>> = BTreeMap::new();
+    for (i, key) in keys.enumerate() {
+        if let Ok(target_id) = lookup_canister(key) {
+            groups.entry(target_id).or_default().push(i);
         }
-    });
+    }
+    groups
+}
 
-    let canister_install_args = Encode!(&CanisterNodeMap {
-        canister_id: controllers_id,
-        start_node: new_start_node,
-        end_node: new_end_node
-    })
-    .unwrap();
+//Send one call per shard in parallel, each carrying its part of the batch,
+//and put the per-entry results back in request order. Entries of a shard
+//whose call failed get its error, entries without a shard get NoShard.
+async fn batch_call<T, R>(
+    entries: Vec<T>,
+    key: fn(&T) -> &String,
+    method: &str,
+) -> Vec<Result<R, RouteError>>
+where
+    T: CandidType + Clone,
+    R: CandidType + for<'de> Deserialize<'de>,
+{
+    let groups = group_by_shard(entries.iter().map(key));
 
-    if install_canister(&create_canister_id, canister_install_args).await {
-        NODE_MAP_LISTS.with(|list_ref| {
-            let mut list = list_ref.borrow_mut();
+    let calls = groups.iter().map(|(target_id, indexes)| {
+        let part: Vec<T> = indexes.iter().map(|i| entries[*i].clone()).collect();
+        try_call::<_, (Vec<R>,)>(*target_id, method, (part,))
+    });
+    let replies = join_all(calls).await;
 
-            //updata node
-            for elem in list.slot_list.iter_mut() {
-                if elem.canister_id == prev_canister_id {
-                    elem.end_node = mid_node;
+    let mut results: Vec<Result<R, RouteError>> = entries
+        .iter()
+        .map(|e| Err(RouteError::NoShard(key_slot(key(e)))))
+        .collect();
+    for ((target_id, indexes), reply) in groups.iter().zip(replies) {
+        match reply {
+            Ok((values,)) => {
+                for (i, value) in indexes.iter().zip(values) {
+                    results[*i] = Ok(value);
+                }
+            }
+            Err(e) => {
+                if let RouteError::Rejected(msg) = &e {
+                    print(format!(
+                        "An error happened during the call to {}: {}",
+                        target_id, msg
+                    ));
+                }
+                for i in indexes {
+                    results[*i] = Err(e.clone());
                 }
             }
+        }
+    }
+    results
+}
 
-            list.slot_list.push(CanisterNodeMap {
-                canister_id: create_canister_id.clone(),
-                start_node: new_start_node.clone(),
-                end_node: new_end_node.clone(),
-            });
-        });
+//A batch is refused whole when the caller may not write one of its keys,
+//otherwise every entry gets its own result
+#[update]
+#[candid::candid_method(update)]
+async fn hmset(
+    entries: Vec<(String, String, Vec<u8>)>,
+) -> Result<Vec<Result<bool, RouteError>>, RouteError> {
+    for (key, _, _) in entries.iter() {
+        check_write(key)?;
+    }
+    Ok(batch_call(entries, |e| &e.0, "hmset").await)
+}
 
-        Ok(CanisterNodeMap {
-            canister_id: create_canister_id.clone(),
-            start_node: new_start_node.clone(),
-            end_node: new_end_node.clone(),
-        })
-    } else {
-        Err("Expand memory false!".to_string())
+#[update]
+#[candid::candid_method(update)]
+async fn hmdel(
+    entries: Vec<(String, String)>,
+) -> Result<Vec<Result<bool, RouteError>>, RouteError> {
+    for (key, _) in entries.iter() {
+        check_write(key)?;
     }
+    Ok(batch_call(entries, |e| &e.0, "hmdel").await)
 }
 
-//Get the currently owned User Canister and Hash slot mapping table
-#[query]
-#[candid::candid_method(query)]
-fn allot_canister_list() -> Vec<CanisterNodeMap> {
-    NODE_MAP_LISTS.with(|list| list.borrow().slot_list.clone())
+#[update]
+#[candid::candid_method(update)]
+async fn hmget(entries: Vec<(String, String)>) -> Vec<Result<Option<Vec<u8>>, RouteError>> {
+    batch_call(entries, |e| &e.0, "hmget").await
 }
 
-// Get the Canister ID of the node
-#[query]
-#[candid::candid_method(query)]
-fn get_correlation_canister(key: String) -> Principal {
-    let mod_size = crc16(&key) % (SLOT_SIZE - 1);
+#[derive(CandidType, Deserialize)]
+pub struct ScanResult {
+    entries: Vec<(String, Vec<u8>)>,
+    next: Option<String>,
+}
 
-    NODE_MAP_LISTS.with(|list| {
-        let list = list.borrow();
-        let vecter_iterator = list.slot_list.iter();
+//Routed reads are update calls, calling a shard from a query needs composite
+//queries which this ic-cdk version does not offer
+#[update]
+#[candid::candid_method(update)]
+async fn hget(key: String, field: String) -> Result<Option<Vec<u8>>, RouteError> {
+    let (value,): (Option<Vec<u8>>,) = try_route(&key, "hget", (&key, field)).await?;
+    Ok(value)
+}
 
-        let mut canister_id: Principal = Principal::from_slice(&[]);
+#[update]
+#[candid::candid_method(update)]
+async fn hexist(key: String, field: String) -> Result<bool, RouteError> {
+    let (exist,): (bool,) = try_route(&key, "hexist", (&key, field)).await?;
+    Ok(exist)
+}
 
-        for elem in vecter_iterator {
-            if mod_size >= elem.start_node && mod_size <= elem.end_node {
-                canister_id = elem.canister_id.clone();
-            }
-        }
-        canister_id
-    })
+#[update]
+#[candid::candid_method(update)]
+async fn hgetall(key: String, cursor: Option<String>) -> Result<ScanResult, RouteError> {
+    let (page,): (ScanResult,) = try_route(&key, "hgetall", (&key, cursor)).await?;
+    Ok(page)
 }
 
-#[query]
-#[candid::candid_method(query)]
-fn verify_canister(pid: Principal) -> bool {
-    is_exisr(pid)
+#[update]
+#[candid::candid_method(update)]
+async fn hscan(key: String, cursor: Option<String>, limit: u32) -> Result<ScanResult, RouteError> {
+    let (page,): (ScanResult,) = try_route(&key, "hscan", (&key, cursor, limit)).await?;
+    Ok(page)
 }
 
 #[update]
 #[candid::candid_method(update)]
-async fn hset(key: String, field: String, value: Vec<u8>) {
-    let target_id = get_correlation_canister(key.clone());
-    match ic_cdk::api::call::call(target_id, "hset", (key, field, value)).await {
-        Ok(x) => x,
-        Err((code, msg)) => {
-            ic_cdk::api::print(format!(
-                "An error happened during the call: {}: {}",
-                code as u8, msg
-            ));
-        }
-    }
+async fn hscan_prefix(
+    key: String,
+    prefix: String,
+    cursor: Option<String>,
+    limit: u32,
+) -> Result<ScanResult, RouteError> {
+    let (page,): (ScanResult,) =
+        try_route(&key, "hscan_prefix", (&key, prefix, cursor, limit)).await?;
+    Ok(page)
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct KeysResult {
+    fields: Vec<String>,
+    next: Option<String>,
 }
 
 #[update]
 #[candid::candid_method(update)]
-async fn hdel(key: String, field: String) {
-    let target_id = get_correlation_canister(key.clone());
-    match ic_cdk::api::call::call(target_id, "hdel", (key, field)).await {
-        Ok(x) => x,
-        Err((code, msg)) => {
-            ic_cdk::api::print(format!(
-                "An error happened during the call: {}: {}",
-                code as u8, msg
-            ));
-        }
-    }
+async fn hkeys(key: String, cursor: Option<String>) -> Result<KeysResult, RouteError> {
+    let (page,): (KeysResult,) = try_route(&key, "hkeys", (&key, cursor)).await?;
+    Ok(page)
 }
 
-#[query]
-#[candid::candid_method(query)]
-async fn hscan(key: String, _field: String) -> Principal {
-    get_correlation_canister(key)
-}
-
-fn crc16(pid: &String) -> u32 {
-    const CUSTOM_ALG: Algorithm<u16> = Algorithm {
-        width: 16,
-        poly: 0x8005,
-        init: 0xffff,
-        refin: false,
-        refout: false,
-        xorout: 0x0000,
-        check: 0xaee7,
-        residue: 0x0000,
-    };
-    let crc = Crc::<u16>::new(&CUSTOM_ALG);
-    let mut digest = crc.digest();
+#[update]
+#[candid::candid_method(update)]
+async fn hlen(key: String) -> Result<u64, RouteError> {
+    let (len,): (u64,) = try_route(&key, "hlen", (&key,)).await?;
+    Ok(len)
+}
+
+#[update]
+#[candid::candid_method(update)]
+async fn hset_ex(
+    key: String,
+    field: String,
+    value: Vec<u8>,
+    expire_at: u64,
+) -> Result<bool, RouteError> {
+    check_write(&key)?;
+    let (stored,): (bool,) = try_route(&key, "hset_ex", (&key, field, value, expire_at)).await?;
+    Ok(stored)
+}
+
+#[update]
+#[candid::candid_method(update)]
+async fn hexpire(
+    key: String,
+    field: Option<String>,
+    expire_at: Option<u64>,
+) -> Result<bool, RouteError> {
+    check_write(&key)?;
+    let (set,): (bool,) = try_route(&key, "hexpire", (&key, field, expire_at)).await?;
+    Ok(set)
+}
+
+#[update]
+#[candid::candid_method(update)]
+async fn httl(key: String, field: Option<String>) -> Result<Option<u64>, RouteError> {
+    let (at,): (Option<u64>,) = try_route(&key, "httl", (&key, field)).await?;
+    Ok(at)
+}
+
+#[update]
+#[candid::candid_method(update)]
+async fn hcount(key: String) -> Result<u64, RouteError> {
+    let (count,): (u64,) = try_route(&key, "hcount", (&key,)).await?;
+    Ok(count)
+}
 
-    digest.update(pid.as_bytes());
+#[update]
+#[candid::candid_method(update)]
+async fn hincrby(key: String, field: String, delta: i64) -> Result<i64, RouteError> {
+    check_write(&key)?;
+    let (result,): (Result<i64, String>,) =
+        try_route(&key, "hincrby", (&key, field, delta)).await?;
+    result.map_err(shard_error)
+}
 
-    digest.finalize() as u32
+#[update]
+#[candid::candid_method(update)]
+async fn hincrby_nat(key: String, field: String, delta: i64) -> Result<u64, RouteError> {
+    check_write(&key)?;
+    let (result,): (Result<u64, String>,) =
+        try_route(&key, "hincrby_nat", (&key, field, delta)).await?;
+    result.map_err(shard_error)
 }
 
 //1.Response capacity expansion message
-//2.insert new canister,update slot node
-//3.send slot node message
+//2.insert new canister for half of the bytes
+//3.send slot node message, the full canister starts copying
+//4.Ok once the full canister is told, until then the shard asks again
 #[update]
-async fn expand_memory() {
+async fn expand_memory() -> Result<(), String> {
     let caller_id = ic_cdk::api::caller();
-    assert_eq!(true, is_exisr(caller_id));
+    assert!(is_exisr(caller_id));
 
     let call_arg = ic_cdk::api::call::arg_data::<(Option<CanisterStateArg>,)>().0;
-    match call_arg {
-        Some(call_value) => {
-            let prev_canister_id = call_value.canister_id;
-            if call_value.is_full == true {
-                let result = insert_single_canister(prev_canister_id.clone()).await;
-
-                match result {
-                    Ok(v) => callback_new_canister_args(prev_canister_id, v).await,
-                    Err(e) => ic_cdk::api::print(format!("{}", e)),
-                }
-            }
-        }
-        None => ic_cdk::api::print("Get capacity expansion information empty!"),
-    };
+    let call_value =
+        call_arg.ok_or_else(|| "Get capacity expansion information empty!".to_string())?;
+    if call_value.is_full {
+        let prev_canister_id = call_value.canister_id;
+        let v = insert_single_canister(prev_canister_id).await?;
+        callback_new_canister_args(prev_canister_id, v).await?;
+    }
+    Ok(())
 }
 
-async fn callback_new_canister_args(to_canister_id: Principal, new_canister_args: CanisterNodeMap) {
-    match ic_cdk::call(to_canister_id, "update_node_data", (new_canister_args,)).await {
-        Ok(x) => x,
-        Err((code, msg)) => {
-            ic_cdk::api::print(format!(
-                "An error happened during the call: {}: {}",
-                code as u8, msg
-            ));
-        }
-    };
+async fn callback_new_canister_args(
+    to_canister_id: Principal,
+    new_canister_args: CanisterNodeMap,
+) -> Result<(), String> {
+    ic_cdk::call(to_canister_id, "update_node_data", (new_canister_args,))
+        .await
+        .map_err(|(code, msg)| {
+            format!("An error happened during the call: {}: {}", code as u8, msg)
+        })
 }
 
 #[pre_upgrade]
@@ -439,10 +1628,12 @@ fn pre_upgrade() {
 
 #[post_upgrade]
 fn post_upgrade() {
+    //Stored maps with gaps or overlaps are repaired while decoding, see SlotMap
     let (old_state,): (CanisterNodeMapList,) = storage::stable_restore().unwrap();
     NODE_MAP_LISTS.with(|allot_ids| {
         *allot_ids.borrow_mut() = old_state;
-    })
+    });
+    start_timers();
 }
 
 #[init]
@@ -450,7 +1641,121 @@ fn init() {
     NODE_MAP_LISTS.with(|owner_ref| {
         let mut owner = owner_ref.borrow_mut();
         owner.owner = ic_cdk::api::caller();
-    })
+    });
+    start_timers();
+}
+
+fn start_timers() {
+    ic_cdk_timers::set_timer_interval(SHARD_CHECK_INTERVAL, || ic_cdk::spawn(check_shards()));
+}
+
+fn cycles_policy() -> CyclesPolicy {
+    NODE_MAP_LISTS.with(|list| list.borrow().cycles_policy.clone().unwrap_or_default())
+}
+
+//Read the status of every shard through the management canister and top up
+//the ones below the policy threshold, then swap in the new snapshot. A shard
+//that can not be checked or funded is listed with the error.
+async fn check_shards() {
+    //Cleared when the check ends, traps included
+    let _running = match RunGuard::try_start(&CHECK_RUNNING) {
+        Some(guard) => guard,
+        None => return,
+    };
+    let policy = cycles_policy();
+    let shards = NODE_MAP_LISTS.with(|list| fleet(&list.borrow()));
+    let mut fleet = ShardFleet::default();
+
+    for canister_id in shards {
+        let mut health = ShardHealth {
+            canister_id,
+            status: None,
+            cycles: None,
+            memory: None,
+            module_hash: None,
+            topped_up: 0,
+            last_error: None,
+        };
+
+        match slot_router::canister_status(canister_id).await {
+            Ok(status) => {
+                let mut cycles = status.cycles;
+                if cycles < policy.threshold {
+                    match top_up_shard(canister_id, policy.top_up).await {
+                        Ok(()) => {
+                            health.topped_up = policy.top_up;
+                            cycles += policy.top_up;
+                        }
+                        Err(err) => health.last_error = Some(err),
+                    }
+                }
+                fleet.total_cycles += cycles.clone();
+                fleet.total_memory += status.memory_size.clone();
+                health.status = Some(status.status);
+                health.cycles = Some(cycles);
+                health.memory = Some(status.memory_size);
+                health.module_hash = status.module_hash;
+            }
+            Err(err) => health.last_error = Some(err),
+        }
+
+        if let Some(err) = &health.last_error {
+            print(format!("Shard {} check: {}", canister_id, err));
+        }
+        fleet.shards.push(health);
+    }
+
+    fleet.updated_at = ic_cdk::api::time();
+    fleet.allot_cycles = ic_cdk::api::canister_balance();
+    SHARD_FLEET.with(|f| *f.borrow_mut() = fleet);
+}
+
+async fn top_up_shard(canister_id: Principal, cycles: u64) -> Result<(), String> {
+    let available = ic_cdk::api::canister_balance().saturating_sub(CYCLES_RESERVE);
+    if available < cycles {
+        return Err(format!(
+            "Error: allot can spare {} cycles, top up needs {}",
+            available, cycles
+        ));
+    }
+    slot_router::deposit_cycles(canister_id, cycles).await
+}
+
+#[query]
+#[candid::candid_method(query)]
+fn shard_health() -> ShardFleet {
+    SHARD_FLEET.with(|f| f.borrow().clone())
+}
+
+#[update]
+#[candid::candid_method(update)]
+async fn refresh_shard_health() -> ShardFleet {
+    assert_eq!(
+        NODE_MAP_LISTS.with(|allot| { allot.borrow().owner }),
+        ic_cdk::api::caller()
+    );
+    check_shards().await;
+    shard_health()
+}
+
+#[query]
+#[candid::candid_method(query)]
+fn get_cycles_policy() -> CyclesPolicy {
+    cycles_policy()
+}
+
+#[update]
+#[candid::candid_method(update)]
+fn set_cycles_policy(policy: CyclesPolicy) -> Result<(), String> {
+    assert_eq!(
+        NODE_MAP_LISTS.with(|allot| { allot.borrow().owner }),
+        ic_cdk::api::caller()
+    );
+    if policy.top_up == 0 {
+        return Err("Error: top_up must be above 0".to_string());
+    }
+    NODE_MAP_LISTS.with(|list| list.borrow_mut().cycles_policy = Some(policy));
+    Ok(())
 }
 
 #[query]
@@ -469,16 +1774,7 @@ async fn wallet_receive() -> () {
 }
 
 pub fn is_exisr(caller_id: Principal) -> bool {
-    let mut exist = false;
-    NODE_MAP_LISTS.with(|lists_ref| {
-        let lists = lists_ref.borrow();
-        for elem in lists.slot_list.iter() {
-            if elem.canister_id == caller_id {
-                exist = true;
-            }
-        }
-    });
-    exist
+    NODE_MAP_LISTS.with(|lists_ref| lists_ref.borrow().slot_list.contains_canister(&caller_id))
 }
 
 candid::export_service!();
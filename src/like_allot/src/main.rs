@@ -1,9 +1,11 @@
-mod lib;
+//The canister wasm is built from this bin, the lib is built for tests
+#[path = "lib.rs"]
+mod canister;
 
-#[cfg(not(any(target_arch = "wasm32", test)))]
+#[cfg(not(target_arch = "wasm32"))]
 fn main() {
-    std::print!("{}", crate::lib::export_candid());
+    std::print!("{}", crate::canister::export_candid());
 }
 
-#[cfg(any(target_arch = "wasm32", test))]
+#[cfg(target_arch = "wasm32")]
 fn main() {}
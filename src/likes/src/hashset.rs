@@ -11,23 +11,11 @@ where
     pub field: HashMap<K, V>,
 }
 
-impl<K, V> Field<K, V>
-where
-    K: Hash + Eq,
-    V: Clone,
-{
-    pub fn new() -> Self {
-        Self {
-            field: HashMap::default(),
-        }
-    }
-}
-
 #[derive(Clone, CandidType, Serialize, Deserialize, Default)]
 pub struct HashSet<K, V>
 where
     K: Hash + Eq,
-    V:Clone,
+    V: Clone,
 {
     pub hset: HashMap<K, Field<K, V>>,
 }
@@ -43,21 +31,6 @@ where
         }
     }
 
-    pub fn insert(&mut self, key: K, field: K, value: V) -> bool {
-        match self.hset.get_mut(&key) {
-            Some(set) => {
-                set.field.insert(field, value);
-                true
-            }
-            None => {
-                let mut insert_field: Field<K, V> = Field::new();
-                insert_field.field.insert(field, value);
-                self.hset.insert(key, insert_field);
-                true
-            }
-        }
-    }
-
     pub fn remove_key(&mut self, key: K) -> bool {
         match self.hset.get_mut(&key) {
             Some(_set) => {
@@ -86,20 +59,13 @@ where
     }
 
     pub fn get_key(&self, key: &K) -> Option<&Field<K, V>> {
-        match self.hset.get(&key) {
-            Some(set) => Some(set),
-            None => None,
-        }
+        self.hset.get(key)
     }
 
     pub fn get_field(&self, key: &K, field: &K) -> Option<V> {
-        match self.hset.get(&key) {
-            Some(set) => match set.field.get(&field) {
-                Some(v) => Some(v.clone()),
-                None => None,
-            },
-            None => None,
-        }
+        self.hset
+            .get(key)
+            .and_then(|set| set.field.get(field).cloned())
     }
 
     pub fn len(&self) -> usize {
@@ -107,7 +73,7 @@ where
     }
 
     pub fn field_len(&self, key: &K) -> usize {
-        match self.hset.get(&key) {
+        match self.hset.get(key) {
             Some(v) => v.field.len(),
             None => 0,
         }
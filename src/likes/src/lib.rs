@@ -1,74 +1,59 @@
 use candid::CandidType;
-use crc::{Algorithm, Crc};
 use ic_cdk::export::{candid, Principal};
 use ic_cdk::storage;
 use ic_cdk_macros::*;
 use serde::{Deserialize, Serialize};
-use std::cell::RefCell;
-use std::mem;
+use slot_router::{CanisterNodeMap, SlotLoad, ERR_SHARD_UNAUTHORIZED};
+use std::cell::{Cell, RefCell};
+use std::time::Duration;
 
 // #[path = "hashset.rs"]
 mod hashset;
+mod migrate;
+mod store;
 use hashset::HashSet;
 
-const SLOT_SIZE: u32 = 65536; //Hash slot size
-const INDEX_LIMIT: usize = 1000;
 const MEMORY_LIMIT: u64 = 2 * 1024 * 1024 * 1024;
-
-#[derive(CandidType, Deserialize)]
-enum InstallMode {
-    #[serde(rename = "install")]
-    Install,
-    #[serde(rename = "reinstall")]
-    Reinstall,
-    #[serde(rename = "upgrade")]
-    Upgrade,
-}
-#[derive(CandidType, Deserialize)]
-struct CanisterInstall {
-    mode: InstallMode,
-    canister_id: Principal,
-    #[serde(with = "serde_bytes")]
-    wasm_module: Vec<u8>,
-    #[serde(with = "serde_bytes")]
-    arg: Vec<u8>,
-}
-
-#[derive(Clone, CandidType, Serialize, Deserialize)]
-pub struct CanisterNodeMap {
-    canister_id: Principal,
-    start_node: u32,
-    end_node: u32,
-}
-
-impl CanisterNodeMap {
-    fn new() -> Self {
-        Self {
-            canister_id: Principal::from_slice(&[]),
-            start_node: 0,
-            end_node: 0,
-        }
-    }
-}
+const SCAN_LIMIT: u32 = 1000; //Max fields returned by one scan page
+const SWEEP_INTERVAL: Duration = Duration::from_secs(30);
+const SWEEP_BATCH: usize = 1000; //Max expired entries reclaimed per sweep
+const LOAD_BATCH: usize = 2000; //Entries counted per slot load rebuild tick
+const CYCLES_RESERVE: u64 = 10_000_000_000; //Kept back when returning cycles, pays for the reply
+const EXPAND_RETRY: Duration = Duration::from_secs(60); //Wait between unanswered split requests
 
 #[derive(Clone, CandidType, Serialize, Deserialize)]
 pub struct CanisterState {
-    likes: HashSet<String, Vec<u8>>,
     init_args: CanisterNodeMap,
-    migrating_data: bool,
     owner: Principal,
+    outgoing: Option<migrate::OutMigration>,
+    queued: Option<Vec<migrate::QueuedMigration>>,
+    incoming: Option<Vec<migrate::InMigration>>,
+    rejected: Option<Vec<migrate::Rejection>>,
+    //stable memory size when the allot last took a split request
+    expand_acked: Option<u64>,
 }
 impl CanisterState {
     fn new() -> Self {
         Self {
-            likes: HashSet::new(),
             init_args: CanisterNodeMap::new(),
-            migrating_data: false,
             owner: Principal::from_slice(&[]),
+            outgoing: None,
+            queued: None,
+            incoming: None,
+            rejected: None,
+            expand_acked: None,
         }
     }
 }
 
+//State layout before the stable store, the whole state was saved with stable_save
+#[derive(CandidType, Deserialize)]
+struct LegacyCanisterState {
+    likes: HashSet<String, Vec<u8>>,
+    init_args: CanisterNodeMap,
+    owner: Principal,
+}
+
 #[derive(CandidType, Deserialize)]
 pub struct CanisterStateArg {
     canister_id: Principal,
@@ -77,92 +62,249 @@ pub struct CanisterStateArg {
 
 thread_local! {
     pub static STATE : RefCell<CanisterState> = RefCell::new(CanisterState::new());
+    static EXPAND_SENT_AT: Cell<u64> = const { Cell::new(0) };
+}
+
+//Only the allot that installed this canister writes to it
+fn check_owner() {
+    if STATE.with(|state| state.borrow().owner) != ic_cdk::api::caller() {
+        ic_cdk::trap(ERR_SHARD_UNAUTHORIZED);
+    }
+}
+
+//Reject writes to a slot range that is changing shards, callers retry later
+fn check_writable(key: &str) {
+    if let Err(e) = migrate::track_write(key) {
+        ic_cdk::trap(&e);
+    }
 }
 
 #[update]
 #[candid::candid_method(update)]
 async fn hset(key: String, field: String, value: Vec<u8>) -> bool {
-    assert_eq!(STATE.with(|state| state.borrow().migrating_data), false);
+    check_owner();
 
-    assert_eq!(
-        STATE.with(|state| state.borrow().owner),
-        ic_cdk::api::caller()
-    );
+    check_writable(&key);
+    let data_state = store::set(key, field, value, None, ic_cdk::api::time());
 
-    let mut data_state = false;
+    read_memory_limit();
+    data_state
+}
 
-    STATE.with(|state_ref| {
-        let mut state = state_ref.borrow_mut();
-        data_state = state.likes.insert(key, field, value);
-    });
+//hset with an expiry, `expire_at` is in nanoseconds since the epoch
+#[update]
+#[candid::candid_method(update)]
+pub fn hset_ex(key: String, field: String, value: Vec<u8>, expire_at: u64) -> bool {
+    check_owner();
+
+    check_writable(&key);
+    let data_state = store::set(key, field, value, Some(expire_at), ic_cdk::api::time());
 
     read_memory_limit();
     data_state
 }
 
+//Set the expiry of a field, or of the whole key when no field is given.
+//None clears it. False when there is nothing live to expire.
+#[update]
+#[candid::candid_method(update)]
+pub fn hexpire(key: String, field: Option<String>, expire_at: Option<u64>) -> bool {
+    check_owner();
+
+    check_writable(&key);
+    store::expire(&key, field.as_deref(), expire_at, ic_cdk::api::time())
+}
+
+#[query]
+#[candid::candid_method(query)]
+pub fn httl(key: String, field: Option<String>) -> Option<u64> {
+    store::ttl(&key, field.as_deref(), ic_cdk::api::time())
+}
+
 #[update]
 #[candid::candid_method(update)]
 pub fn hdel(key: String, field: String) -> bool {
-    assert_eq!(STATE.with(|state| state.borrow().migrating_data), false);
+    check_owner();
 
-    assert_eq!(
-        STATE.with(|state| state.borrow().owner),
-        ic_cdk::api::caller()
-    );
+    check_writable(&key);
+    store::remove(&key, &field, ic_cdk::api::time())
+}
 
-    STATE.with(|state_ref| {
-        let mut state = state_ref.borrow_mut();
-        state.likes.remove_field(key, field)
-    })
+//Atomic add on a signed counter field, the value is kept as decimal text
+#[update]
+#[candid::candid_method(update)]
+pub fn hincrby(key: String, field: String, delta: i64) -> Result<i64, String> {
+    check_owner();
+
+    migrate::track_write(&key)?;
+    let result = store::incr_by(key, field, delta, ic_cdk::api::time());
+
+    read_memory_limit();
+    result
+}
+
+//Like hincrby, fails instead of going below zero
+#[update]
+#[candid::candid_method(update)]
+pub fn hincrby_nat(key: String, field: String, delta: i64) -> Result<u64, String> {
+    check_owner();
+
+    migrate::track_write(&key)?;
+    let result = store::incr_by_nat(key, field, delta, ic_cdk::api::time());
+
+    read_memory_limit();
+    result
+}
+
+#[update]
+#[candid::candid_method(update)]
+pub fn hmset(entries: Vec<(String, String, Vec<u8>)>) -> Vec<bool> {
+    check_owner();
+
+    for (key, _, _) in entries.iter() {
+        check_writable(key);
+    }
+
+    let now = ic_cdk::api::time();
+    let data_state = entries
+        .into_iter()
+        .map(|(key, field, value)| store::set(key, field, value, None, now))
+        .collect();
+
+    read_memory_limit();
+    data_state
+}
+
+#[update]
+#[candid::candid_method(update)]
+pub fn hmdel(entries: Vec<(String, String)>) -> Vec<bool> {
+    check_owner();
+
+    for (key, _) in entries.iter() {
+        check_writable(key);
+    }
+
+    let now = ic_cdk::api::time();
+    entries
+        .iter()
+        .map(|(key, field)| store::remove(key, field, now))
+        .collect()
+}
+
+#[query]
+#[candid::candid_method(query)]
+pub fn hmget(entries: Vec<(String, String)>) -> Vec<Option<Vec<u8>>> {
+    let now = ic_cdk::api::time();
+    entries
+        .iter()
+        .map(|(key, field)| store::get(key, field, now))
+        .collect()
 }
 
 #[query]
 #[candid::candid_method(query)]
 pub fn hget(key: String, field: String) -> Option<Vec<u8>> {
-    STATE.with(|state_ref| {
-        let state = state_ref.borrow();
-        state.likes.get_field(&key, &field)
-    })
+    store::get(&key, &field, ic_cdk::api::time())
 }
 
 #[query]
 #[candid::candid_method(query)]
 pub fn hexist(key: String, field: String) -> bool {
-    STATE.with(|state_ref| {
-        let state = state_ref.borrow();
-        let ex = state.likes.get_field(&key, &field);
-        match ex {
-            Some(_v) => true,
-            None => false,
-        }
-    })
+    store::get(&key, &field, ic_cdk::api::time()).is_some()
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct ScanResult {
+    entries: Vec<(String, Vec<u8>)>,
+    next: Option<String>,
 }
 
-//Registered user data collected,read canister memory capacity,triggered "Full" message
+//Fields and values of a key, SCAN_LIMIT at a time. Pass the returned `next`
+//as cursor to continue.
+#[query]
+#[candid::candid_method(query)]
+pub fn hgetall(key: String, cursor: Option<String>) -> ScanResult {
+    hscan_prefix(key, String::new(), cursor, SCAN_LIMIT)
+}
+
+//A page of fields of a key, pass the returned `next` as cursor to continue
+#[query]
+#[candid::candid_method(query)]
+pub fn hscan(key: String, cursor: Option<String>, limit: u32) -> ScanResult {
+    hscan_prefix(key, String::new(), cursor, limit)
+}
+
+#[query]
+#[candid::candid_method(query)]
+pub fn hscan_prefix(key: String, prefix: String, cursor: Option<String>, limit: u32) -> ScanResult {
+    let limit = limit.min(SCAN_LIMIT) as usize;
+    let (entries, next) = store::scan(&key, &prefix, cursor.as_deref(), limit, ic_cdk::api::time());
+    ScanResult { entries, next }
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct KeysResult {
+    fields: Vec<String>,
+    next: Option<String>,
+}
+
+//Fields of a key, paged like hgetall
+#[query]
+#[candid::candid_method(query)]
+pub fn hkeys(key: String, cursor: Option<String>) -> KeysResult {
+    let page = hgetall(key, cursor);
+    KeysResult {
+        fields: page.entries.into_iter().map(|(f, _)| f).collect(),
+        next: page.next,
+    }
+}
+
+#[query]
+#[candid::candid_method(query)]
+pub fn hlen(key: String) -> u64 {
+    store::count(&key, ic_cdk::api::time())
+}
+
+//Number of live fields of a key, read from the kept counts
+#[query]
+#[candid::candid_method(query)]
+pub fn hcount(key: String) -> u64 {
+    store::count(&key, ic_cdk::api::time())
+}
+
+//Ask the allot for a split while stable memory is over the limit, again
+//every EXPAND_RETRY until it takes the request. Stable memory never shrinks,
+//so after a split the shard asks again only once it grew past the size at
+//the last request taken.
 fn read_memory_limit() {
-    STATE.with(|state| {
-        let index = state.borrow().likes.len();
-
-        //Check memory capacity after storing a batch of users
-        if (index % INDEX_LIMIT) == 0 {
-            //Capacity greater than 2 GB,triggered "Full" message
-            if read_stable_memory_size() > MEMORY_LIMIT {
-                send_expand_memory();
-            }
-        }
-    });
+    let used = stable_memory_size();
+    let acked = STATE.with(|state| state.borrow().expand_acked.unwrap_or(0));
+    if used <= MEMORY_LIMIT || used <= acked || migrate::outgoing().is_some() {
+        return;
+    }
+
+    let now = ic_cdk::api::time();
+    let retry = EXPAND_RETRY.as_nanos() as u64;
+    if EXPAND_SENT_AT.with(|sent_at| now.saturating_sub(sent_at.get()) < retry) {
+        return;
+    }
+    EXPAND_SENT_AT.with(|sent_at| sent_at.set(now));
+    send_expand_memory(used);
 }
 
 //Send users allot canister capacity expansion information
-fn send_expand_memory() {
-    ic_cdk::spawn(async {
+fn send_expand_memory(used: u64) {
+    ic_cdk::spawn(async move {
         let allot_id = STATE.with(|state| state.borrow().init_args.canister_id);
         let send_arg: CanisterStateArg = CanisterStateArg {
             canister_id: ic_cdk::api::id(),
             is_full: true,
         };
-        match ic_cdk::call(allot_id.clone(), "expand_memory", (send_arg,)).await {
-            Ok(x) => x,
+        let result: Result<(Result<(), String>,), _> =
+            ic_cdk::call(allot_id, "expand_memory", (send_arg,)).await;
+        match result {
+            Ok((Ok(()),)) => STATE.with(|state| state.borrow_mut().expand_acked = Some(used)),
+            Ok((Err(e),)) => ic_cdk::api::print(format!("Split request refused: {}", e)),
             Err((code, msg)) => {
                 ic_cdk::api::print(format!(
                     "An error happened during the call: {}: {}",
@@ -173,9 +315,8 @@ fn send_expand_memory() {
     });
 }
 
-//1.Get users allot canister update node and data information
-//2.Split user data by new node
-//3.Send user data to new inserted canister
+//The allot hands a slot range of this canister to a new one, the transfer
+//runs on timers, see migrate.rs
 #[update]
 async fn update_node_data() {
     assert_eq!(ic_cdk::api::caller(), get_allot_id());
@@ -183,143 +324,201 @@ async fn update_node_data() {
     let call_arg = ic_cdk::api::call::arg_data::<(Option<CanisterNodeMap>,)>().0;
 
     match call_arg {
-        Some(call_value) => {
-            let move_data = division_migration_data(call_value.start_node, call_value.end_node);
-            sand_migration_data(call_value.canister_id, move_data).await;
-        }
+        Some(call_value) => migrate::start(
+            call_value.canister_id,
+            call_value.start_node,
+            call_value.end_node,
+        ),
         None => ic_cdk::api::print("Update node call error!"),
     };
 }
 
-//Move user data of corresponding node to new canister
-async fn sand_migration_data(to_canister_id: Principal, move_data: HashSet<String, Vec<u8>>) {
-    for (k, v) in move_data.hset {
-        let mut data: HashSet<String, Vec<u8>> = HashSet::new();
-        data.hset.insert(k, v);
-        match ic_cdk::call(to_canister_id, "receive_migration_data", (data,)).await {
-            Ok(x) => x,
-            Err((code, msg)) => {
-                ic_cdk::api::print(format!(
-                    "An error happened during the call: {}: {}",
-                    code as u8, msg
-                ));
-            }
-        };
-    }
-}
-
 #[update]
-async fn receive_migration_data(move_data: HashSet<String, Vec<u8>>) {
+#[candid::candid_method(update)]
+async fn receive_migration_data(batch: migrate::MigrationBatch) -> Result<(), String> {
     let caller = ic_cdk::api::caller();
-    let allot_id = get_allot_id();
 
-    match ic_cdk::call(allot_id, "verify_canister", (caller,)).await {
-        Ok(x) => x,
-        Err((code, msg)) => {
-            ic_cdk::api::print(format!(
-                "An error happened during the call verify_canister: {}: {}",
-                code as u8, msg
-            ));
-            return;
-        }
+    //The allot is asked once per transfer, later batches come from the same shard
+    let range = match migrate::incoming_range(caller) {
+        Some(range) => Ok(range),
+        None => verify_migration(caller).await,
+    };
+    let result = range.and_then(|range| migrate::receive(caller, range, batch));
+    if let Err(e) = &result {
+        migrate::log_rejected(caller, e);
     }
-    STATE.with(|state| state.borrow_mut().migrating_data = true);
-    put_data(move_data);
-    STATE.with(|state| state.borrow_mut().migrating_data = false);
+    result
 }
 
-fn put_data(likes: HashSet<String, Vec<u8>>) {
-    STATE.with(|state_ref| {
-        let mut state = state_ref.borrow_mut();
-        for (k, v) in likes.hset {
-            state.likes.hset.insert(k, v);
-        }
-    });
+//Slot range the allot is moving from the `source` shard to this canister
+async fn verify_migration(source: Principal) -> Result<migrate::SlotRange, String> {
+    match ic_cdk::call::<_, (Option<migrate::SlotRange>,)>(
+        get_allot_id(),
+        "verify_migration",
+        (source,),
+    )
+    .await
+    {
+        Ok((Some(range),)) => Ok(range),
+        Ok((None,)) => Err(format!(
+            "Error: {} is not moving slots to this canister",
+            source
+        )),
+        Err((code, msg)) => Err(format!(
+            "An error happened during the call verify_migration: {}: {}",
+            code as u8, msg
+        )),
+    }
 }
 
-//Get node interval value and move data
-fn division_migration_data(start_node: u32, end_node: u32) -> HashSet<String, Vec<u8>> {
-    STATE.with(|state| state.borrow_mut().migrating_data = true);
+#[query]
+#[candid::candid_method(query)]
+fn migration_rejections() -> Vec<migrate::Rejection> {
+    migrate::rejections()
+}
 
-    STATE.with(|state_ref| {
-        let mut original_data = state_ref.borrow_mut();
+#[update]
+#[candid::candid_method(update)]
+fn confirm_migration(source: Principal, seq: u64, entries: u64) -> Result<(), String> {
+    assert_eq!(ic_cdk::api::caller(), get_allot_id());
 
-        let mut move_data: HashSet<String, Vec<u8>> = HashSet::new();
-        for (k, v) in original_data.likes.hset.iter() {
-            let mod_size = crc16(&k) % (SLOT_SIZE - 1);
-            if mod_size >= start_node && mod_size <= end_node {
-                move_data.hset.insert(k.clone(), v.clone());
-            }
-        }
+    migrate::confirm(source, seq, entries)
+}
 
-        original_data.likes.hset.retain(|k, _| {
-            (crc16(&k.to_string()) % (SLOT_SIZE - 1)) < start_node
-                || (crc16(&k.to_string()) % (SLOT_SIZE - 1)) > end_node
-        });
+#[query]
+#[candid::candid_method(query)]
+fn migration_status() -> migrate::MigrationStatus {
+    migrate::status()
+}
 
-        STATE.with(|state| state.borrow_mut().migrating_data = false);
-        move_data
-    })
+//Keys and bytes of the slots in start..=end holding data, the allot picks
+//split points with it
+#[query]
+#[candid::candid_method(query)]
+fn slot_loads(start: u32, end: u32) -> Result<Vec<SlotLoad>, String> {
+    store::slot_loads(start, end)
+        .ok_or_else(|| "Error: slot loads are still being counted".to_string())
 }
 
-//canister stable memory
+//bytes of keys, fields and values held by this canister
 #[query(name = "memory_size")]
 pub fn read_stable_memory_size() -> u64 {
+    store::data_bytes()
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct MemoryStats {
+    data_bytes: u64,
+    heap_bytes: u64,
+    stable_bytes: u64,
+    limit: u64,
+}
+
+#[query]
+#[candid::candid_method(query)]
+fn memory_stats() -> MemoryStats {
+    MemoryStats {
+        data_bytes: store::data_bytes(),
+        heap_bytes: heap_memory_size(),
+        stable_bytes: stable_memory_size(),
+        limit: MEMORY_LIMIT,
+    }
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct LegacyStatus {
+    //data of the old layout not moved into the stable store yet
+    pending: bool,
+    error: Option<String>,
+}
+
+#[query]
+#[candid::candid_method(query)]
+fn legacy_status() -> LegacyStatus {
+    LegacyStatus {
+        pending: store::has_legacy(),
+        error: store::legacy_error(),
+    }
+}
+
+//canister heap memory
+pub fn heap_memory_size() -> u64 {
     #[cfg(target_arch = "wasm32")]
     {
-        users_memory_size() as u64
+        (core::arch::wasm32::memory_size(0) as u64) * 65536
     }
     #[cfg(not(target_arch = "wasm32"))]
     {
-        (ic_cdk::api::stable::stable_size() as u64) * 65536
+        0
     }
 }
 
 //canister stable memory
 #[query(name = "st_memory_size")]
 pub fn stable_memory_size() -> u64 {
-    (ic_cdk::api::stable::stable_size() as u64) * 65536
+    ic_cdk::api::stable::stable64_size() * 65536
 }
 
-pub fn users_memory_size() -> usize {
-    STATE.with(|state_ref| {
-        let state = state_ref.borrow();
-        hset_size(&state.likes)
-    })
+#[pre_upgrade]
+fn pre_upgrade() {
+    STATE.with(|state| store::save(&*state.borrow()));
 }
 
-fn crc16(pid: &String) -> u32 {
-    const CUSTOM_ALG: Algorithm<u16> = Algorithm {
-        width: 16,
-        poly: 0x8005,
-        init: 0xffff,
-        refin: false,
-        refout: false,
-        xorout: 0x0000,
-        check: 0xaee7,
-        residue: 0x0000,
-    };
-    let crc = Crc::<u16>::new(&CUSTOM_ALG);
-    let mut digest = crc.digest();
-
-    digest.update(pid.as_bytes());
+#[post_upgrade]
+fn post_upgrade() {
+    //Must run before the memory manager takes over stable memory
+    if store::is_legacy_layout() {
+        let (old_state,): (LegacyCanisterState,) = storage::stable_restore().unwrap();
+        store::set_legacy(old_state.likes);
+        STATE.with(|state| {
+            *state.borrow_mut() = CanisterState {
+                init_args: old_state.init_args,
+                owner: old_state.owner,
+                outgoing: None,
+                queued: None,
+                incoming: None,
+                rejected: None,
+                expand_acked: None,
+            };
+        });
+    } else if let Some(old_state) = store::load::<CanisterState>() {
+        STATE.with(|state| {
+            *state.borrow_mut() = old_state;
+        });
+    }
+    schedule_legacy_drain();
+    schedule_load_rebuild();
+    start_sweeper();
+    migrate::schedule(Duration::ZERO);
+}
 
-    digest.finalize() as u32
+//Move legacy data into the stable store a batch per timer tick, an entry that
+//can not be moved stops it, see legacy_status
+fn schedule_legacy_drain() {
+    if store::has_legacy() {
+        ic_cdk_timers::set_timer(Duration::ZERO, || match store::drain_legacy() {
+            Ok(_) => schedule_legacy_drain(),
+            Err(e) => ic_cdk::api::print(e),
+        });
+    }
 }
 
-#[pre_upgrade]
-fn pre_upgrade() {
-    STATE.with(|state| {
-        storage::stable_save((state,)).unwrap();
-    })
+//Count the entries stored before slot loads were kept a batch per tick
+fn schedule_load_rebuild() {
+    ic_cdk_timers::set_timer(Duration::ZERO, || {
+        if !store::rebuild_loads(LOAD_BATCH) {
+            schedule_load_rebuild();
+        }
+    });
 }
 
-#[post_upgrade]
-fn post_upgrade() {
-    let (old_state,): (CanisterState,) = storage::stable_restore().unwrap();
-    STATE.with(|state| {
-        *state.borrow_mut() = old_state;
-    })
+//Reclaim expired entries a batch per tick
+fn start_sweeper() {
+    ic_cdk_timers::set_timer_interval(SWEEP_INTERVAL, || {
+        store::sweep_expired(SWEEP_BATCH, ic_cdk::api::time());
+        //Keeps asking for a split when no writes come in
+        read_memory_limit();
+    });
 }
 
 #[init]
@@ -333,6 +532,8 @@ fn init() {
         }),
         None => ic_cdk::api::print("Get caninster init args error!"),
     }
+    start_sweeper();
+    schedule_load_rebuild();
 }
 
 #[query]
@@ -350,18 +551,25 @@ async fn wallet_receive() -> () {
     };
 }
 
-pub fn hset_size(hash_set: &HashSet<String, Vec<u8>>) -> usize {
-    let mut size = 0;
-    for (k, v) in &hash_set.hset {
-        let mut f_k_s: usize = mem::size_of::<String>() + mem::size_of_val(&**k);
-        let mut f_v_s: usize = mem::size_of_val(v);
-        for (f_k, f_v) in &v.field {
-            f_k_s += std::mem::size_of::<String>() + std::mem::size_of_val(&**f_k);
-            f_v_s += std::mem::size_of_val(f_v) + std::mem::size_of_val(&**f_v);
-        }
-        size += f_k_s + f_v_s;
+//The allot takes the cycles back before it deletes this canister
+#[update]
+#[candid::candid_method(update)]
+async fn return_cycles() -> Result<u64, String> {
+    let allot_id = get_allot_id();
+    if ic_cdk::api::caller() != allot_id {
+        return Err(ERR_SHARD_UNAUTHORIZED.to_string());
     }
-    size
+
+    let cycles = ic_cdk::api::canister_balance().saturating_sub(CYCLES_RESERVE);
+    slot_router::deposit_cycles(allot_id, cycles).await?;
+    Ok(cycles)
+}
+
+//Passed back unchanged when the allot upgrades this shard
+#[query]
+#[candid::candid_method(query)]
+fn init_args() -> CanisterNodeMap {
+    STATE.with(|state| state.borrow().init_args.clone())
 }
 
 fn get_allot_id() -> Principal {
@@ -0,0 +1,11 @@
+//The canister wasm is built from this bin, the lib is built for tests
+#[path = "lib.rs"]
+mod canister;
+
+#[cfg(not(target_arch = "wasm32"))]
+fn main() {
+    std::print!("{}", crate::canister::export_candid());
+}
+
+#[cfg(target_arch = "wasm32")]
+fn main() {}
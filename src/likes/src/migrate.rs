@@ -0,0 +1,866 @@
+use candid::CandidType;
+use ic_cdk::export::Principal;
+use serde::{Deserialize, Serialize};
+use std::cell::Cell;
+use std::collections::BTreeSet;
+use std::time::Duration;
+
+use super::store::{self, LikeKey};
+use super::{get_allot_id, STATE};
+use slot_router::{key_slot, RunGuard, ERR_SHARD_MIGRATING};
+
+const BATCH_ENTRIES: usize = 500; //Entries sent per migration call
+const REPLAY_PAGE: usize = 50; //Fields of a dirty key read at a time
+const SCAN_BUDGET: usize = 20_000; //Entries looked at per copy or delete step
+const RETRY_DELAY: Duration = Duration::from_secs(10);
+const MAX_REJECTIONS: usize = 100; //Refused migration batches kept for audit
+
+thread_local! {
+    static RUNNING: Cell<bool> = const { Cell::new(false) };
+}
+
+//Steps of handing a slot range to another shard:
+//copy the range, replay keys written meanwhile, wait for the allot to verify
+//the copy and flip the routing, then delete the range here
+#[derive(Clone, CandidType, Serialize, Deserialize, PartialEq)]
+pub enum OutPhase {
+    Copying,
+    Replaying,
+    Sealed,
+    Deleting,
+}
+
+//Slot range leaving this shard, kept in the canister state so the transfer
+//resumes after an upgrade
+#[derive(Clone, CandidType, Serialize, Deserialize)]
+pub struct OutMigration {
+    pub target: Principal,
+    pub start_node: u32,
+    pub end_node: u32,
+    pub phase: OutPhase,
+    //last entry copied or deleted
+    pub cursor: Option<(String, String)>,
+    //key too large for one replay batch and the last of its fields sent
+    pub replay: Option<(String, String)>,
+    //batches and entries the target acknowledged
+    pub seq: u64,
+    pub entries: u64,
+}
+
+//Transfer the allot handed over while another one was running, started
+//once the running one is done
+#[derive(Clone, CandidType, Serialize, Deserialize)]
+pub struct QueuedMigration {
+    pub target: Principal,
+    pub start_node: u32,
+    pub end_node: u32,
+}
+
+//Slot range arriving from another shard
+#[derive(Clone, CandidType, Serialize, Deserialize)]
+pub struct InMigration {
+    pub source: Principal,
+    pub start_node: u32,
+    pub end_node: u32,
+    pub next_seq: u64,
+    pub entries: u64,
+    //the allot confirmed the copy, kept so a confirm retried after a failed
+    //flip is accepted again, replaced by the next transfer from the source
+    pub confirmed: bool,
+}
+
+#[derive(Clone, CandidType, Deserialize)]
+pub struct MigrationEntry {
+    pub key: String,
+    pub field: String,
+    #[serde(with = "serde_bytes")]
+    pub value: Vec<u8>,
+    pub expire_at: Option<u64>,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct MigrationBatch {
+    pub seq: u64,
+    pub start_node: u32,
+    pub end_node: u32,
+    //keys cleared on the target before the entries are applied
+    pub replace: Vec<String>,
+    pub entries: Vec<MigrationEntry>,
+    pub key_expiries: Vec<(String, u64)>,
+}
+
+//Slot range the allot moves to this canister, decoded from its SlotMigration
+#[derive(CandidType, Deserialize)]
+pub struct SlotRange {
+    pub start_node: u32,
+    pub end_node: u32,
+}
+
+#[derive(Clone, CandidType, Serialize, Deserialize)]
+pub struct Rejection {
+    pub caller: Principal,
+    pub time: u64,
+    pub reason: String,
+}
+
+//What the source sent, checked by the allot against what the target applied
+#[derive(CandidType, Deserialize)]
+pub struct MigrationReport {
+    pub target: Principal,
+    pub start_node: u32,
+    pub end_node: u32,
+    pub seq: u64,
+    pub entries: u64,
+}
+
+pub fn outgoing() -> Option<OutMigration> {
+    STATE.with(|state| state.borrow().outgoing.clone())
+}
+
+fn set_outgoing(out: Option<OutMigration>) {
+    STATE.with(|state| state.borrow_mut().outgoing = out);
+}
+
+fn in_range(out: &OutMigration, key: &str) -> bool {
+    let slot = key_slot(key);
+    slot >= out.start_node && slot <= out.end_node
+}
+
+//Writes to a leaving range are replayed while it is copied and refused from
+//the moment the copy is sealed until the range is deleted here. Writes to an
+//arriving range are refused until the allot confirmed the copy.
+pub fn track_write(key: &str) -> Result<(), String> {
+    if let Some(out) = outgoing() {
+        if in_range(&out, key) {
+            match out.phase {
+                OutPhase::Copying | OutPhase::Replaying => store::mark_dirty(key),
+                OutPhase::Sealed | OutPhase::Deleting => {
+                    return Err(ERR_SHARD_MIGRATING.to_string())
+                }
+            }
+        }
+    }
+
+    let slot = key_slot(key);
+    let arriving = STATE.with(|state| {
+        state
+            .borrow()
+            .incoming
+            .iter()
+            .flatten()
+            .any(|m| !m.confirmed && slot >= m.start_node && slot <= m.end_node)
+    });
+    if arriving {
+        return Err(ERR_SHARD_MIGRATING.to_string());
+    }
+    Ok(())
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct MigrationStatus {
+    outgoing: Option<OutMigration>,
+    queued: Vec<QueuedMigration>,
+    incoming: Vec<InMigration>,
+}
+
+pub fn status() -> MigrationStatus {
+    STATE.with(|state| {
+        let state = state.borrow();
+        MigrationStatus {
+            outgoing: state.outgoing.clone(),
+            queued: state.queued.clone().unwrap_or_default(),
+            incoming: state.incoming.clone().unwrap_or_default(),
+        }
+    })
+}
+
+fn new_outgoing(target: Principal, start_node: u32, end_node: u32) -> OutMigration {
+    OutMigration {
+        target,
+        start_node,
+        end_node,
+        phase: OutPhase::Copying,
+        cursor: None,
+        replay: None,
+        seq: 0,
+        entries: 0,
+    }
+}
+
+pub fn start(target: Principal, start_node: u32, end_node: u32) {
+    match outgoing() {
+        Some(out) if out.target != target => STATE.with(|state| {
+            let mut state = state.borrow_mut();
+            let queued = state.queued.get_or_insert_with(Vec::new);
+            if !queued.iter().any(|m| m.target == target) {
+                ic_cdk::api::print(format!(
+                    "Migration to {} still running, queue {}",
+                    out.target, target
+                ));
+                queued.push(QueuedMigration {
+                    target,
+                    start_node,
+                    end_node,
+                });
+            }
+        }),
+        Some(_) => {}
+        None => set_outgoing(Some(new_outgoing(target, start_node, end_node))),
+    }
+    schedule(Duration::ZERO);
+}
+
+//Take the oldest queued transfer
+fn next_queued() -> Option<OutMigration> {
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        let queued = state.queued.as_mut().filter(|q| !q.is_empty())?;
+        let next = queued.remove(0);
+        Some(new_outgoing(next.target, next.start_node, next.end_node))
+    })
+}
+
+//Run the next migration step on a timer, a single chain at a time
+pub fn schedule(delay: Duration) {
+    if outgoing().is_none() {
+        return;
+    }
+    //Held by the timer until the step ends, a step that traps drops it too
+    let running = match RunGuard::try_start(&RUNNING) {
+        Some(guard) => guard,
+        None => return,
+    };
+    ic_cdk_timers::set_timer(delay, move || {
+        ic_cdk::spawn(async move {
+            let next = step().await;
+            drop(running);
+            if let Some(delay) = next {
+                schedule(delay);
+            }
+        })
+    });
+}
+
+async fn step() -> Option<Duration> {
+    let out = outgoing()?;
+    match out.phase {
+        OutPhase::Copying => copy_step(out).await,
+        OutPhase::Replaying => replay_step(out, ic_cdk::api::time()).await,
+        OutPhase::Sealed => verify_step(out).await,
+        OutPhase::Deleting => delete_step(out),
+    }
+}
+
+fn entry(key: String, field: String, value: Vec<u8>) -> MigrationEntry {
+    let expire_at = store::expire_at(&key, Some(&field));
+    MigrationEntry {
+        key,
+        field,
+        value,
+        expire_at,
+    }
+}
+
+fn key_expiries(entries: &[MigrationEntry]) -> Vec<(String, u64)> {
+    let keys: BTreeSet<&String> = entries.iter().map(|e| &e.key).collect();
+    keys.into_iter()
+        .filter_map(|k| store::expire_at(k, None).map(|at| (k.clone(), at)))
+        .collect()
+}
+
+async fn send(out: &OutMigration, replace: Vec<String>, entries: Vec<MigrationEntry>) -> bool {
+    let batch = MigrationBatch {
+        seq: out.seq,
+        start_node: out.start_node,
+        end_node: out.end_node,
+        replace,
+        key_expiries: key_expiries(&entries),
+        entries,
+    };
+    match ic_cdk::call::<_, (Result<(), String>,)>(out.target, "receive_migration_data", (batch,))
+        .await
+    {
+        Ok((Ok(()),)) => true,
+        Ok((Err(e),)) => {
+            ic_cdk::api::print(format!("Migration batch {} refused: {}", out.seq, e));
+            false
+        }
+        Err((code, msg)) => {
+            ic_cdk::api::print(format!(
+                "An error happened during the call receive_migration_data: {}: {}",
+                code as u8, msg
+            ));
+            false
+        }
+    }
+}
+
+//Copy the next entries of the range, the source keeps them until the end
+async fn copy_step(mut out: OutMigration) -> Option<Duration> {
+    //Legacy entries are not in the stable map yet
+    if store::has_legacy() {
+        return Some(RETRY_DELAY);
+    }
+    let cursor = out
+        .cursor
+        .clone()
+        .map(|(key, field)| LikeKey { key, field });
+    let (found, last, done) = store::range_batch(
+        cursor.as_ref(),
+        |k| in_range(&out, k),
+        BATCH_ENTRIES,
+        SCAN_BUDGET,
+    );
+
+    let entries: Vec<MigrationEntry> = found
+        .into_iter()
+        .map(|(k, v)| entry(k.key, k.field, v))
+        .collect();
+    let sent = entries.len() as u64;
+    if sent > 0 && !send(&out, vec![], entries).await {
+        return Some(RETRY_DELAY);
+    }
+
+    if sent > 0 {
+        out.seq += 1;
+        out.entries += sent;
+    }
+    out.cursor = last.map(|k| (k.key, k.field));
+    if done {
+        out.phase = OutPhase::Replaying;
+    }
+    set_outgoing(Some(out));
+    Some(Duration::ZERO)
+}
+
+//Next replay batch, the keys it clears on the target and the entries it
+//sends, capped by entries and value bytes
+struct Replay {
+    replace: Vec<String>,
+    entries: Vec<MigrationEntry>,
+    bytes: u64,
+    //where a key too large for the batch stopped
+    rest: Option<(String, String)>,
+}
+
+impl Replay {
+    fn full(&self) -> bool {
+        self.entries.len() >= BATCH_ENTRIES || self.bytes >= store::BATCH_BYTES
+    }
+
+    //Add the fields of `key` after `after` until the batch is full, false
+    //when fields are left over. `after` is then the last field added.
+    fn add_key(&mut self, key: &str, after: &mut Option<String>, now: u64) -> bool {
+        loop {
+            let (page, next) = store::scan(key, "", after.as_deref(), REPLAY_PAGE, now);
+            for (field, value) in page {
+                let size = value.len() as u64;
+                //a single larger value goes on its own
+                if !self.entries.is_empty()
+                    && (self.entries.len() == BATCH_ENTRIES
+                        || self.bytes + size > store::BATCH_BYTES)
+                {
+                    return false;
+                }
+                self.bytes += size;
+                *after = Some(field.clone());
+                self.entries.push(entry(key.to_string(), field, value));
+            }
+            if next.is_none() {
+                return true;
+            }
+        }
+    }
+}
+
+//Finish the key the last batch stopped in, then take dirty keys while there
+//is room. A key split over several batches is cleared on the target only by
+//the first of them.
+fn next_replay(rest: Option<(String, String)>, now: u64) -> Replay {
+    let mut replay = Replay {
+        replace: vec![],
+        entries: vec![],
+        bytes: 0,
+        rest: None,
+    };
+    if let Some((key, field)) = rest {
+        let mut after = Some(field);
+        if !replay.add_key(&key, &mut after, now) {
+            replay.rest = after.map(|field| (key, field));
+            return replay;
+        }
+    }
+    while !replay.full() {
+        let key = match store::take_dirty(1).pop() {
+            Some(key) => key,
+            None => break,
+        };
+        let mut after = None;
+        if replay.add_key(&key, &mut after, now) {
+            replay.replace.push(key);
+            continue;
+        }
+        match after {
+            Some(field) => {
+                replay.replace.push(key.clone());
+                replay.rest = Some((key, field));
+            }
+            //no room for any of its fields, left for the next batch
+            None => store::mark_dirty(&key),
+        }
+        break;
+    }
+    replay
+}
+
+//Send the current content of keys written during the copy. A key written
+//again while its batch is in flight is marked dirty again and resent.
+async fn replay_step(out: OutMigration, now: u64) -> Option<Duration> {
+    let replay = next_replay(out.replay.clone(), now);
+    if replay.replace.is_empty() && replay.entries.is_empty() {
+        //No await since the last check, so nothing was written in between
+        let mut out = out;
+        out.phase = OutPhase::Sealed;
+        out.replay = None;
+        set_outgoing(Some(out));
+        return Some(Duration::ZERO);
+    }
+
+    let sent = replay.entries.len() as u64;
+    if !send(&out, replay.replace.clone(), replay.entries).await {
+        for k in replay.replace.iter() {
+            store::mark_dirty(k);
+        }
+        return Some(RETRY_DELAY);
+    }
+
+    //Re-read, writes during the await only touched the dirty set
+    let mut out = outgoing().unwrap_or(out);
+    out.seq += 1;
+    out.entries += sent;
+    out.replay = replay.rest;
+    set_outgoing(Some(out));
+    Some(Duration::ZERO)
+}
+
+//Ask the allot to check the copy against the target and flip the routing
+async fn verify_step(mut out: OutMigration) -> Option<Duration> {
+    let report = MigrationReport {
+        target: out.target,
+        start_node: out.start_node,
+        end_node: out.end_node,
+        seq: out.seq,
+        entries: out.entries,
+    };
+    match ic_cdk::call::<_, (Result<(), String>,)>(get_allot_id(), "migration_copied", (report,))
+        .await
+    {
+        Ok((Ok(()),)) => {
+            out.phase = OutPhase::Deleting;
+            out.cursor = None;
+            set_outgoing(Some(out));
+            Some(Duration::ZERO)
+        }
+        Ok((Err(e),)) => {
+            ic_cdk::api::print(format!("Migration to {} not confirmed: {}", out.target, e));
+            Some(RETRY_DELAY)
+        }
+        Err((code, msg)) => {
+            ic_cdk::api::print(format!(
+                "An error happened during the call migration_copied: {}: {}",
+                code as u8, msg
+            ));
+            Some(RETRY_DELAY)
+        }
+    }
+}
+
+//The target owns the range now, drop the local copy a batch at a time
+fn delete_step(mut out: OutMigration) -> Option<Duration> {
+    let cursor = out
+        .cursor
+        .clone()
+        .map(|(key, field)| LikeKey { key, field });
+    let (found, last, done) = store::range_batch(
+        cursor.as_ref(),
+        |k| in_range(&out, k),
+        BATCH_ENTRIES,
+        SCAN_BUDGET,
+    );
+    for (k, _) in found {
+        store::discard(&k.key, &k.field);
+    }
+
+    if done {
+        ic_cdk::api::print(format!(
+            "Migrated {} entries to {}",
+            out.entries, out.target
+        ));
+        let next = next_queued();
+        let delay = next.as_ref().map(|_| Duration::ZERO);
+        set_outgoing(next);
+        return delay;
+    }
+    out.cursor = last.map(|k| (k.key, k.field));
+    set_outgoing(Some(out));
+    Some(Duration::ZERO)
+}
+
+//Transfer from `source` still being copied
+fn find_incoming(source: Principal) -> Option<InMigration> {
+    STATE.with(|state| {
+        state
+            .borrow()
+            .incoming
+            .iter()
+            .flatten()
+            .find(|m| m.source == source && !m.confirmed)
+            .cloned()
+    })
+}
+
+//Range of a transfer from `source` already accepted by this canister
+pub fn incoming_range(source: Principal) -> Option<SlotRange> {
+    find_incoming(source).map(|m| SlotRange {
+        start_node: m.start_node,
+        end_node: m.end_node,
+    })
+}
+
+pub fn log_rejected(caller: Principal, reason: &str) {
+    ic_cdk::api::print(format!(
+        "Migration batch from {} rejected: {}",
+        caller, reason
+    ));
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        let log = state.rejected.get_or_insert_with(Vec::new);
+        if log.len() == MAX_REJECTIONS {
+            log.remove(0);
+        }
+        log.push(Rejection {
+            caller,
+            time: ic_cdk::api::time(),
+            reason: reason.to_string(),
+        });
+    });
+}
+
+pub fn rejections() -> Vec<Rejection> {
+    STATE.with(|state| state.borrow().rejected.clone().unwrap_or_default())
+}
+
+//Every key of the batch must hash into the range being transferred
+fn check_batch(range: &SlotRange, batch: &MigrationBatch) -> Result<(), String> {
+    if batch.start_node != range.start_node || batch.end_node != range.end_node {
+        return Err(format!(
+            "Error: batch for slots {}..={}, transfer is {}..={}",
+            batch.start_node, batch.end_node, range.start_node, range.end_node
+        ));
+    }
+    let keys = batch
+        .replace
+        .iter()
+        .chain(batch.entries.iter().map(|e| &e.key))
+        .chain(batch.key_expiries.iter().map(|(k, _)| k));
+    for k in keys {
+        let slot = key_slot(k);
+        if slot < range.start_node || slot > range.end_node {
+            return Err(format!(
+                "Error: key {} in slot {} outside the transfer",
+                k, slot
+            ));
+        }
+    }
+    Ok(())
+}
+
+//Apply a batch from the source shard of a transfer of `range` the allot
+//confirmed. Batches are applied in order once, a batch resent after a lost
+//reply is acknowledged without applying it again.
+pub fn receive(source: Principal, range: SlotRange, batch: MigrationBatch) -> Result<(), String> {
+    check_batch(&range, &batch)?;
+
+    let mut incoming = match find_incoming(source) {
+        Some(m) => m,
+        None if batch.seq == 0 => InMigration {
+            source,
+            start_node: range.start_node,
+            end_node: range.end_node,
+            next_seq: 0,
+            entries: 0,
+            confirmed: false,
+        },
+        None => return Err(format!("Error: no migration from {}", source)),
+    };
+    if batch.seq < incoming.next_seq {
+        return Ok(());
+    }
+    if batch.seq > incoming.next_seq {
+        return Err(format!(
+            "Error: expected batch {}, got {}",
+            incoming.next_seq, batch.seq
+        ));
+    }
+
+    for k in batch.replace.iter() {
+        store::purge(k);
+    }
+    incoming.entries += batch.entries.len() as u64;
+    let now = ic_cdk::api::time();
+    for e in batch.entries {
+        let (key, field) = (e.key.clone(), e.field.clone());
+        if store::insert(e.key, e.field, e.value, now) {
+            store::set_expire_at(&key, Some(&field), e.expire_at);
+        }
+    }
+    for (k, at) in batch.key_expiries {
+        store::set_expire_at(&k, None, Some(at));
+    }
+    incoming.next_seq += 1;
+
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        let list = state.incoming.get_or_insert_with(Vec::new);
+        list.retain(|m| m.source != source);
+        list.push(incoming);
+    });
+    Ok(())
+}
+
+//Called by the allot before it flips the routing, the target must have
+//applied exactly what the source sent. Confirming the same transfer again
+//succeeds, the allot retries when the flip or its reply failed.
+pub fn confirm(source: Principal, seq: u64, entries: u64) -> Result<(), String> {
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        let list = state.incoming.get_or_insert_with(Vec::new);
+        let incoming = match list.iter_mut().find(|m| m.source == source) {
+            Some(m) => m,
+            None if seq == 0 => return Ok(()),
+            None => return Err(format!("Error: no migration from {}", source)),
+        };
+        if incoming.next_seq != seq || incoming.entries != entries {
+            return Err(format!(
+                "Error: received {} batches {} entries, sent {} batches {} entries",
+                incoming.next_seq, incoming.entries, seq, entries
+            ));
+        }
+        incoming.confirmed = true;
+        Ok(())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::future::Future;
+    use std::pin::pin;
+    use std::task::{Context, Poll, Waker};
+
+    //Steps that make no call finish on their first poll
+    fn run<T>(step: impl Future<Output = T>) -> T {
+        let mut cx = Context::from_waker(Waker::noop());
+        match pin!(step).poll(&mut cx) {
+            Poll::Ready(value) => value,
+            Poll::Pending => panic!("step made a call"),
+        }
+    }
+
+    fn leaving(phase: OutPhase) -> OutMigration {
+        let slot = key_slot("inside");
+        OutMigration {
+            phase,
+            ..new_outgoing(Principal::anonymous(), slot, slot)
+        }
+    }
+
+    fn phase() -> Option<OutPhase> {
+        outgoing().map(|out| out.phase)
+    }
+
+    #[test]
+    fn writes_are_replayed_while_copying_and_refused_once_sealed() {
+        for phase in [OutPhase::Copying, OutPhase::Replaying] {
+            set_outgoing(Some(leaving(phase)));
+            assert!(track_write("inside").is_ok());
+            assert_eq!(store::take_dirty(10), vec!["inside".to_string()]);
+        }
+        for phase in [OutPhase::Sealed, OutPhase::Deleting] {
+            set_outgoing(Some(leaving(phase)));
+            assert_eq!(track_write("inside"), Err(ERR_SHARD_MIGRATING.to_string()));
+        }
+        assert!(store::take_dirty(10).is_empty());
+    }
+
+    #[test]
+    fn an_empty_copy_moves_on_to_replaying() {
+        run(copy_step(leaving(OutPhase::Copying)));
+        assert!(phase() == Some(OutPhase::Replaying));
+    }
+
+    #[test]
+    fn replaying_without_dirty_keys_seals_the_range() {
+        run(replay_step(leaving(OutPhase::Replaying), 0));
+        assert!(phase() == Some(OutPhase::Sealed));
+    }
+
+    #[test]
+    fn a_queued_transfer_starts_copying() {
+        STATE.with(|state| {
+            state.borrow_mut().queued = Some(vec![QueuedMigration {
+                target: Principal::anonymous(),
+                start_node: 1,
+                end_node: 2,
+            }])
+        });
+        let next = next_queued().expect("queued transfer");
+        assert!(next.phase == OutPhase::Copying);
+        assert_eq!((next.start_node, next.end_node, next.seq), (1, 2, 0));
+        assert!(next_queued().is_none());
+    }
+
+    fn batch(range: &SlotRange, keys: &[&str]) -> MigrationBatch {
+        MigrationBatch {
+            seq: 0,
+            start_node: range.start_node,
+            end_node: range.end_node,
+            replace: vec![],
+            entries: keys
+                .iter()
+                .map(|k| MigrationEntry {
+                    key: k.to_string(),
+                    field: "f".to_string(),
+                    value: vec![],
+                    expire_at: None,
+                })
+                .collect(),
+            key_expiries: vec![],
+        }
+    }
+
+    fn range_of(key: &str) -> SlotRange {
+        let slot = key_slot(key);
+        SlotRange {
+            start_node: slot,
+            end_node: slot,
+        }
+    }
+
+    //A key hashing into another slot than `key`
+    fn outside(key: &str) -> String {
+        (0..)
+            .map(|i| format!("other{}", i))
+            .find(|k| key_slot(k) != key_slot(key))
+            .unwrap()
+    }
+
+    #[test]
+    fn a_batch_inside_the_range_is_accepted() {
+        let range = range_of("inside");
+        assert!(check_batch(&range, &batch(&range, &["inside", "inside"])).is_ok());
+    }
+
+    #[test]
+    fn a_batch_for_another_range_is_rejected() {
+        let range = range_of("inside");
+        let mut b = batch(&range, &["inside"]);
+        b.end_node += 1;
+        assert!(check_batch(&range, &b).is_err());
+    }
+
+    #[test]
+    fn keys_outside_the_range_are_rejected() {
+        let range = range_of("inside");
+        let other = outside("inside");
+
+        assert!(check_batch(&range, &batch(&range, &["inside", &other])).is_err());
+
+        let mut b = batch(&range, &["inside"]);
+        b.replace = vec![other.clone()];
+        assert!(check_batch(&range, &b).is_err());
+
+        let mut b = batch(&range, &["inside"]);
+        b.key_expiries = vec![(other, 1)];
+        assert!(check_batch(&range, &b).is_err());
+    }
+
+    const LARGE: usize = store::BATCH_BYTES as usize * 3 / 5;
+
+    fn dirty_key(key: &str, fields: usize, size: usize) {
+        for i in 0..fields {
+            store::insert(key.to_string(), format!("f{}", i), vec![1; size], 0);
+        }
+        store::mark_dirty(key);
+    }
+
+    fn fields(replay: &Replay) -> Vec<String> {
+        replay.entries.iter().map(|e| e.field.clone()).collect()
+    }
+
+    #[test]
+    fn a_large_key_is_replayed_over_several_batches() {
+        dirty_key("viral", 3, LARGE);
+
+        let first = next_replay(None, 0);
+        assert_eq!(first.replace, ["viral"]);
+        assert_eq!(fields(&first), ["f0"]);
+        let second = next_replay(first.rest, 0);
+        assert!(second.replace.is_empty());
+        assert_eq!(fields(&second), ["f1"]);
+        let third = next_replay(second.rest, 0);
+        assert!(third.replace.is_empty());
+        assert_eq!(fields(&third), ["f2"]);
+        assert!(third.rest.is_none());
+    }
+
+    #[test]
+    fn a_key_without_room_stays_dirty() {
+        dirty_key("a", 1, LARGE);
+        dirty_key("b", 1, LARGE);
+
+        let replay = next_replay(None, 0);
+        assert_eq!(replay.replace, ["a"]);
+        assert!(replay.rest.is_none());
+        assert_eq!(store::take_dirty(10), ["b"]);
+    }
+
+    #[test]
+    fn small_keys_share_a_batch() {
+        dirty_key("a", 2, 10);
+        dirty_key("b", 1, 10);
+
+        let replay = next_replay(None, 0);
+        assert_eq!(replay.replace, ["a", "b"]);
+        assert_eq!(fields(&replay), ["f0", "f1", "f0"]);
+        assert!(next_replay(None, 0).entries.is_empty());
+    }
+
+    fn arriving(next_seq: u64, entries: u64) {
+        let slot = key_slot("inside");
+        STATE.with(|state| {
+            state.borrow_mut().incoming = Some(vec![InMigration {
+                source: Principal::anonymous(),
+                start_node: slot,
+                end_node: slot,
+                next_seq,
+                entries,
+                confirmed: false,
+            }])
+        });
+    }
+
+    #[test]
+    fn a_confirm_can_be_retried() {
+        arriving(2, 5);
+        assert!(track_write("inside").is_err());
+        assert!(confirm(Principal::anonymous(), 2, 4).is_err());
+
+        assert!(confirm(Principal::anonymous(), 2, 5).is_ok());
+        assert!(confirm(Principal::anonymous(), 2, 5).is_ok());
+        assert!(confirm(Principal::anonymous(), 3, 5).is_err());
+        assert!(track_write("inside").is_ok());
+        //a later transfer from the same shard is verified with the allot again
+        assert!(incoming_range(Principal::anonymous()).is_none());
+    }
+}
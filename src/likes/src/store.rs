@@ -0,0 +1,1321 @@
+use candid::{CandidType, Decode, Deserialize, Encode};
+use ic_stable_structures::memory_manager::{MemoryId, MemoryManager, VirtualMemory};
+use ic_stable_structures::writer::Writer;
+use ic_stable_structures::{
+    BoundedStorable, DefaultMemoryImpl, Memory, StableBTreeMap, StableCell, Storable,
+};
+use std::borrow::Cow;
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::ops::Bound;
+use std::str::FromStr;
+
+use super::hashset::{Field, HashSet};
+use slot_router::{key_slot, SlotLoad};
+
+type VMemory = VirtualMemory<DefaultMemoryImpl>;
+
+//The maps reserve their max key and value size for every entry, keep them tight
+pub const MAX_KEY_SIZE: usize = 128; //Max bytes of a key or a field
+const INLINE_SIZE: usize = 64; //Values up to this size are kept in LIKES
+const CHUNK_SIZE: usize = 512; //Longer values are split over CHUNKS
+pub const BATCH_BYTES: u64 = 1024 * 1024; //Value bytes returned by one range batch
+const LEGACY_BATCH: usize = 500; //Keys moved out of the legacy set per timer tick
+
+const STATE_MEMORY: MemoryId = MemoryId::new(0);
+const LIKES_MEMORY: MemoryId = MemoryId::new(1);
+const LEGACY_MEMORY: MemoryId = MemoryId::new(2);
+const BYTES_MEMORY: MemoryId = MemoryId::new(3);
+const COUNTS_MEMORY: MemoryId = MemoryId::new(4);
+const EXPIRES_MEMORY: MemoryId = MemoryId::new(5);
+const DEADLINES_MEMORY: MemoryId = MemoryId::new(6);
+const DIRTY_MEMORY: MemoryId = MemoryId::new(7);
+const LOADS_MEMORY: MemoryId = MemoryId::new(8);
+const LOAD_CURSOR_MEMORY: MemoryId = MemoryId::new(9);
+const CHUNKS_MEMORY: MemoryId = MemoryId::new(10);
+
+//Composite key, entries of one key are adjacent and ordered by field
+#[derive(Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct LikeKey {
+    pub key: String,
+    pub field: String,
+}
+
+impl Storable for LikeKey {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        let mut bytes = Vec::with_capacity(2 + self.key.len() + self.field.len());
+        bytes.extend_from_slice(&(self.key.len() as u16).to_be_bytes());
+        bytes.extend_from_slice(self.key.as_bytes());
+        bytes.extend_from_slice(self.field.as_bytes());
+        Cow::Owned(bytes)
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        let len = u16::from_be_bytes([bytes[0], bytes[1]]) as usize;
+        Self {
+            key: String::from_utf8(bytes[2..2 + len].to_vec()).unwrap(),
+            field: String::from_utf8(bytes[2 + len..].to_vec()).unwrap(),
+        }
+    }
+}
+
+impl BoundedStorable for LikeKey {
+    const MAX_SIZE: u32 = 2 + 2 * MAX_KEY_SIZE as u32;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+//Value of an entry, kept in LIKES when it is short, otherwise only its length
+//is kept there and the bytes are in CHUNKS
+pub enum LikeValue {
+    Inline(Vec<u8>),
+    Chunked(u64),
+}
+
+pub type Entry = (LikeKey, Vec<u8>);
+
+impl LikeValue {
+    fn len(&self) -> u64 {
+        match self {
+            LikeValue::Inline(v) => v.len() as u64,
+            LikeValue::Chunked(len) => *len,
+        }
+    }
+
+    fn chunks(&self) -> u32 {
+        match self {
+            LikeValue::Inline(_) => 0,
+            LikeValue::Chunked(len) => len.div_ceil(CHUNK_SIZE as u64) as u32,
+        }
+    }
+}
+
+impl Storable for LikeValue {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        let mut bytes = vec![];
+        match self {
+            LikeValue::Inline(v) => {
+                bytes.push(0);
+                bytes.extend_from_slice(v);
+            }
+            LikeValue::Chunked(len) => {
+                bytes.push(1);
+                bytes.extend_from_slice(&len.to_be_bytes());
+            }
+        }
+        Cow::Owned(bytes)
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        match bytes[0] {
+            0 => LikeValue::Inline(bytes[1..].to_vec()),
+            _ => {
+                let mut len = [0u8; 8];
+                len.copy_from_slice(&bytes[1..9]);
+                LikeValue::Chunked(u64::from_be_bytes(len))
+            }
+        }
+    }
+}
+
+impl BoundedStorable for LikeValue {
+    const MAX_SIZE: u32 = 1 + INLINE_SIZE as u32;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+//Part `index` of a chunked value
+#[derive(Clone, PartialEq, Eq, PartialOrd, Ord)]
+struct ChunkKey {
+    entry: LikeKey,
+    index: u32,
+}
+
+impl Storable for ChunkKey {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        let (key, field) = (&self.entry.key, &self.entry.field);
+        let mut bytes = Vec::with_capacity(8 + key.len() + field.len());
+        bytes.extend_from_slice(&(key.len() as u16).to_be_bytes());
+        bytes.extend_from_slice(key.as_bytes());
+        bytes.extend_from_slice(&(field.len() as u16).to_be_bytes());
+        bytes.extend_from_slice(field.as_bytes());
+        bytes.extend_from_slice(&self.index.to_be_bytes());
+        Cow::Owned(bytes)
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        let key_len = u16::from_be_bytes([bytes[0], bytes[1]]) as usize;
+        let field_at = 2 + key_len;
+        let field_len = u16::from_be_bytes([bytes[field_at], bytes[field_at + 1]]) as usize;
+        let index_at = field_at + 2 + field_len;
+        let mut index = [0u8; 4];
+        index.copy_from_slice(&bytes[index_at..index_at + 4]);
+        Self {
+            entry: LikeKey {
+                key: String::from_utf8(bytes[2..field_at].to_vec()).unwrap(),
+                field: String::from_utf8(bytes[field_at + 2..index_at].to_vec()).unwrap(),
+            },
+            index: u32::from_be_bytes(index),
+        }
+    }
+}
+
+impl BoundedStorable for ChunkKey {
+    const MAX_SIZE: u32 = 8 + 2 * MAX_KEY_SIZE as u32;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+struct Chunk(Vec<u8>);
+
+impl Storable for Chunk {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Borrowed(&self.0)
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Self(bytes.into_owned())
+    }
+}
+
+impl BoundedStorable for Chunk {
+    const MAX_SIZE: u32 = CHUNK_SIZE as u32;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+//Key of the per key field counts
+#[derive(Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct CountKey(pub String);
+
+impl Storable for CountKey {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Borrowed(self.0.as_bytes())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Self(String::from_utf8(bytes.into_owned()).unwrap())
+    }
+}
+
+impl BoundedStorable for CountKey {
+    const MAX_SIZE: u32 = MAX_KEY_SIZE as u32;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+//Target of an expiry, a single field or the whole key when `field` is None
+#[derive(Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct ExpiryKey {
+    pub key: String,
+    pub field: Option<String>,
+}
+
+impl Storable for ExpiryKey {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        let field = self.field.as_deref().unwrap_or("");
+        let mut bytes = Vec::with_capacity(3 + self.key.len() + field.len());
+        bytes.extend_from_slice(&(self.key.len() as u16).to_be_bytes());
+        bytes.extend_from_slice(self.key.as_bytes());
+        bytes.push(self.field.is_some() as u8);
+        bytes.extend_from_slice(field.as_bytes());
+        Cow::Owned(bytes)
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        let len = u16::from_be_bytes([bytes[0], bytes[1]]) as usize;
+        let field = match bytes[2 + len] {
+            0 => None,
+            _ => Some(String::from_utf8(bytes[3 + len..].to_vec()).unwrap()),
+        };
+        Self {
+            key: String::from_utf8(bytes[2..2 + len].to_vec()).unwrap(),
+            field,
+        }
+    }
+}
+
+impl BoundedStorable for ExpiryKey {
+    const MAX_SIZE: u32 = 3 + 2 * MAX_KEY_SIZE as u32;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+//Entry of the expiry queue, ordered by time first
+#[derive(Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Deadline {
+    pub at: u64,
+    pub target: ExpiryKey,
+}
+
+impl Storable for Deadline {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        let mut bytes = self.at.to_be_bytes().to_vec();
+        bytes.extend_from_slice(&self.target.to_bytes());
+        Cow::Owned(bytes)
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        let mut at = [0u8; 8];
+        at.copy_from_slice(&bytes[..8]);
+        Self {
+            at: u64::from_be_bytes(at),
+            target: ExpiryKey::from_bytes(Cow::Borrowed(&bytes[8..])),
+        }
+    }
+}
+
+impl BoundedStorable for Deadline {
+    const MAX_SIZE: u32 = 8 + ExpiryKey::MAX_SIZE;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+//How far the entries stored before slot loads were kept have been counted
+#[derive(Clone, PartialEq)]
+enum LoadCursor {
+    Start,
+    At(LikeKey),
+    Done,
+}
+
+impl Storable for LoadCursor {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        match self {
+            LoadCursor::Start => Cow::Owned(vec![]),
+            LoadCursor::Done => Cow::Owned(vec![1]),
+            LoadCursor::At(k) => {
+                let mut bytes = vec![2];
+                bytes.extend_from_slice(&k.to_bytes());
+                Cow::Owned(bytes)
+            }
+        }
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        match bytes.first() {
+            None => LoadCursor::Start,
+            Some(1) => LoadCursor::Done,
+            Some(_) => LoadCursor::At(LikeKey::from_bytes(Cow::Borrowed(&bytes[1..]))),
+        }
+    }
+}
+
+thread_local! {
+    static MEMORY_MANAGER: RefCell<MemoryManager<DefaultMemoryImpl>> =
+        RefCell::new(MemoryManager::init(DefaultMemoryImpl::default()));
+
+    static LIKES: RefCell<StableBTreeMap<LikeKey, LikeValue, VMemory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(LIKES_MEMORY))),
+    );
+
+    //Parts of the values longer than INLINE_SIZE
+    static CHUNKS: RefCell<StableBTreeMap<ChunkKey, Chunk, VMemory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(CHUNKS_MEMORY))),
+    );
+
+    //Bytes of keys, fields and values stored, kept up to date on every write
+    static DATA_BYTES: RefCell<StableCell<u64, VMemory>> = RefCell::new(
+        StableCell::init(MEMORY_MANAGER.with(|m| m.borrow().get(BYTES_MEMORY)), 0)
+            .expect("data bytes memory error"),
+    );
+
+    //Number of fields of each key in LIKES, legacy fields are counted by LEGACY
+    static COUNTS: RefCell<StableBTreeMap<CountKey, u64, VMemory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(COUNTS_MEMORY))),
+    );
+
+    //Expiry time in nanoseconds of fields and keys that have one
+    static EXPIRES: RefCell<StableBTreeMap<ExpiryKey, u64, VMemory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(EXPIRES_MEMORY))),
+    );
+
+    //Same expiries ordered by time, consumed by the sweeper
+    static DEADLINES: RefCell<StableBTreeMap<Deadline, (), VMemory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(DEADLINES_MEMORY))),
+    );
+
+    //Keys of a migrating slot range written while it is being copied
+    static DIRTY: RefCell<StableBTreeMap<CountKey, (), VMemory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(DIRTY_MEMORY))),
+    );
+
+    //Keys and bytes of LIKES per slot, entries the load cursor has not
+    //reached yet are left out
+    static LOADS: RefCell<StableBTreeMap<u32, (u64, u64), VMemory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(LOADS_MEMORY))),
+    );
+
+    static LOAD_CURSOR: RefCell<StableCell<LoadCursor, VMemory>> = RefCell::new(
+        StableCell::init(MEMORY_MANAGER.with(|m| m.borrow().get(LOAD_CURSOR_MEMORY)), LoadCursor::Start)
+            .expect("load cursor memory error"),
+    );
+
+    //Data restored from the old Candid blob layout, not yet moved into LIKES
+    static LEGACY: RefCell<HashSet<String, Vec<u8>>> = RefCell::new(HashSet::new());
+
+    //Why moving the legacy data stopped
+    static LEGACY_ERROR: RefCell<Option<String>> = const { RefCell::new(None) };
+}
+
+//Values have no size limit, keys and fields are stored in LIKES as they are
+pub fn valid_entry(key: &str, field: &str) -> bool {
+    key.len() <= MAX_KEY_SIZE && field.len() <= MAX_KEY_SIZE
+}
+
+fn entry_size(key: &str, field: &str, value: &[u8]) -> u64 {
+    (key.len() + field.len() + value.len()) as u64
+}
+
+fn stored_size(lk: &LikeKey, value: &LikeValue) -> u64 {
+    (lk.key.len() + lk.field.len()) as u64 + value.len()
+}
+
+fn chunk_key(lk: &LikeKey, index: u32) -> ChunkKey {
+    ChunkKey {
+        entry: lk.clone(),
+        index,
+    }
+}
+
+fn drop_chunks(lk: &LikeKey, from: u32, to: u32) {
+    CHUNKS.with(|chunks| {
+        let mut chunks = chunks.borrow_mut();
+        for index in from..to {
+            chunks.remove(&chunk_key(lk, index));
+        }
+    });
+}
+
+//Store a value under `lk`, returns what it replaced
+fn put_value(lk: &LikeKey, value: Vec<u8>) -> Option<LikeValue> {
+    let stored = if value.len() <= INLINE_SIZE {
+        LikeValue::Inline(value)
+    } else {
+        CHUNKS.with(|chunks| {
+            let mut chunks = chunks.borrow_mut();
+            for (index, chunk) in value.chunks(CHUNK_SIZE).enumerate() {
+                chunks.insert(chunk_key(lk, index as u32), Chunk(chunk.to_vec()));
+            }
+        });
+        LikeValue::Chunked(value.len() as u64)
+    };
+    let new_chunks = stored.chunks();
+    let old = LIKES.with(|likes| likes.borrow_mut().insert(lk.clone(), stored));
+    if let Some(old) = &old {
+        drop_chunks(lk, new_chunks, old.chunks());
+    }
+    old
+}
+
+fn read_value(lk: &LikeKey, stored: LikeValue) -> Vec<u8> {
+    match stored {
+        LikeValue::Inline(v) => v,
+        LikeValue::Chunked(len) => CHUNKS.with(|chunks| {
+            let chunks = chunks.borrow();
+            let mut value = Vec::with_capacity(len as usize);
+            for index in 0..LikeValue::Chunked(len).chunks() {
+                let chunk = chunks
+                    .get(&chunk_key(lk, index))
+                    .expect("missing value chunk");
+                value.extend_from_slice(&chunk.0);
+            }
+            value
+        }),
+    }
+}
+
+fn remove_value(lk: &LikeKey) -> Option<LikeValue> {
+    let old = LIKES.with(|likes| likes.borrow_mut().remove(lk));
+    if let Some(old) = &old {
+        drop_chunks(lk, 0, old.chunks());
+    }
+    old
+}
+
+fn fields_size(key: &str, fields: &Field<String, Vec<u8>>) -> u64 {
+    fields
+        .field
+        .iter()
+        .map(|(f, v)| entry_size(key, f, v))
+        .sum()
+}
+
+fn add_bytes(add: u64, sub: u64) {
+    DATA_BYTES.with(|bytes| {
+        let mut bytes = bytes.borrow_mut();
+        let total = bytes.get().saturating_add(add).saturating_sub(sub);
+        bytes.set(total).expect("data bytes write error");
+    });
+}
+
+pub fn data_bytes() -> u64 {
+    DATA_BYTES.with(|bytes| *bytes.borrow().get())
+}
+
+fn add_count(key: &str, add: u64, sub: u64) {
+    let (before, total) = COUNTS.with(|counts| {
+        let mut counts = counts.borrow_mut();
+        let ck = CountKey(key.to_string());
+        let before = counts.get(&ck).unwrap_or(0);
+        let total = before.saturating_add(add).saturating_sub(sub);
+        if total == 0 {
+            counts.remove(&ck);
+        } else {
+            counts.insert(ck, total);
+        }
+        (before, total)
+    });
+    if before == 0 && total > 0 {
+        add_slot_keys(key, 1, 0);
+    } else if before > 0 && total == 0 {
+        add_slot_keys(key, 0, 1);
+    }
+}
+
+fn load_cursor() -> LoadCursor {
+    LOAD_CURSOR.with(|cursor| cursor.borrow().get().clone())
+}
+
+fn set_load_cursor(at: LoadCursor) {
+    LOAD_CURSOR.with(|cursor| {
+        cursor
+            .borrow_mut()
+            .set(at)
+            .expect("load cursor write error");
+    });
+}
+
+//Add `add` and take `sub` (keys, bytes) from the load of the key's slot
+fn update_load(key: &str, add: (u64, u64), sub: (u64, u64)) {
+    let slot = key_slot(key);
+    LOADS.with(|loads| {
+        let mut loads = loads.borrow_mut();
+        let (keys, bytes) = loads.get(&slot).unwrap_or_default();
+        let keys = keys.saturating_add(add.0).saturating_sub(sub.0);
+        let bytes = bytes.saturating_add(add.1).saturating_sub(sub.1);
+        if keys == 0 && bytes == 0 {
+            loads.remove(&slot);
+        } else {
+            loads.insert(slot, (keys, bytes));
+        }
+    });
+}
+
+//Writes only change the loads of entries the rebuild already counted, it
+//counts the others with their value at the time it reaches them
+fn add_slot_bytes(lk: &LikeKey, add: u64, sub: u64) {
+    let counted = match load_cursor() {
+        LoadCursor::Start => false,
+        LoadCursor::At(cursor) => *lk <= cursor,
+        LoadCursor::Done => true,
+    };
+    if counted {
+        update_load(&lk.key, (0, add), (0, sub));
+    }
+}
+
+//A key is counted by the rebuild with the first of its entries it reaches
+fn add_slot_keys(key: &str, add: u64, sub: u64) {
+    let counted = match load_cursor() {
+        LoadCursor::Start => false,
+        LoadCursor::At(cursor) => key <= cursor.key.as_str(),
+        LoadCursor::Done => true,
+    };
+    if counted {
+        update_load(key, (add, 0), (sub, 0));
+    }
+}
+
+//Count up to `budget` entries stored before slot loads were kept, returns
+//true once every entry is counted
+pub fn rebuild_loads(budget: usize) -> bool {
+    let after = match load_cursor() {
+        LoadCursor::Start => None,
+        LoadCursor::At(k) => Some(k),
+        LoadCursor::Done => return true,
+    };
+    let (entries, last, done) = range_batch(after.as_ref(), |_| true, budget, budget);
+
+    let mut prev_key = after.map(|k| k.key);
+    for (k, v) in entries {
+        let new_key = prev_key.as_deref() != Some(k.key.as_str());
+        update_load(
+            &k.key,
+            (new_key as u64, entry_size(&k.key, &k.field, &v)),
+            (0, 0),
+        );
+        prev_key = Some(k.key);
+    }
+
+    match (done, last) {
+        (true, _) => set_load_cursor(LoadCursor::Done),
+        (false, Some(last)) => set_load_cursor(LoadCursor::At(last)),
+        (false, None) => {}
+    }
+    done
+}
+
+//Loads of the slots in start..=end holding data, None while the rebuild is
+//still counting older entries
+pub fn slot_loads(start: u32, end: u32) -> Option<Vec<SlotLoad>> {
+    if load_cursor() != LoadCursor::Done {
+        return None;
+    }
+    LOADS.with(|loads| {
+        Some(
+            loads
+                .borrow()
+                .range(start..=end)
+                .map(|(slot, (keys, bytes))| SlotLoad { slot, keys, bytes })
+                .collect(),
+        )
+    })
+}
+
+//Number of live fields of a key, fields that expired on their own are
+//looked up in the expiries and left out
+pub fn count(key: &str, now: u64) -> u64 {
+    if key_expired(key, now) {
+        return 0;
+    }
+    stored_count(key).saturating_sub(expired_fields(key, now))
+}
+
+fn stored_count(key: &str) -> u64 {
+    let stored = COUNTS.with(|counts| counts.borrow().get(&CountKey(key.to_string())));
+    let legacy = LEGACY.with(|legacy| legacy.borrow().field_len(&key.to_string()));
+    stored.unwrap_or(0) + legacy as u64
+}
+
+fn expiry_key(key: &str, field: Option<&str>) -> ExpiryKey {
+    ExpiryKey {
+        key: key.to_string(),
+        field: field.map(|f| f.to_string()),
+    }
+}
+
+pub fn expire_at(key: &str, field: Option<&str>) -> Option<u64> {
+    EXPIRES.with(|expires| expires.borrow().get(&expiry_key(key, field)))
+}
+
+fn key_expired(key: &str, now: u64) -> bool {
+    expire_at(key, None).is_some_and(|at| at <= now)
+}
+
+fn field_expired(key: &str, field: &str, now: u64) -> bool {
+    expire_at(key, Some(field)).is_some_and(|at| at <= now)
+}
+
+fn expired(key: &str, field: &str, now: u64) -> bool {
+    key_expired(key, now) || field_expired(key, field, now)
+}
+
+//Fields of a key whose own expiry passed and the sweeper did not reclaim yet
+fn expired_fields(key: &str, now: u64) -> u64 {
+    let start = expiry_key(key, Some(""));
+    EXPIRES.with(|expires| {
+        expires
+            .borrow()
+            .range(start..)
+            .take_while(|(k, _)| k.key == key)
+            .filter(|(_, at)| *at <= now)
+            .count() as u64
+    })
+}
+
+//Set or clear (`at` = None) the expiry of a field or a whole key
+pub fn set_expire_at(key: &str, field: Option<&str>, at: Option<u64>) {
+    let target = expiry_key(key, field);
+    let old = EXPIRES.with(|expires| {
+        let mut expires = expires.borrow_mut();
+        match at {
+            Some(at) => expires.insert(target.clone(), at),
+            None => expires.remove(&target),
+        }
+    });
+    DEADLINES.with(|deadlines| {
+        let mut deadlines = deadlines.borrow_mut();
+        if let Some(old) = old {
+            deadlines.remove(&Deadline {
+                at: old,
+                target: target.clone(),
+            });
+        }
+        if let Some(at) = at {
+            deadlines.insert(Deadline { at, target }, ());
+        }
+    });
+}
+
+fn live(key: &str, field: Option<&str>, now: u64) -> bool {
+    match field {
+        Some(f) => get(key, f, now).is_some(),
+        None => count(key, now) > 0,
+    }
+}
+
+//Expiry of a live field or key
+pub fn ttl(key: &str, field: Option<&str>, now: u64) -> Option<u64> {
+    if live(key, field, now) {
+        expire_at(key, field)
+    } else {
+        None
+    }
+}
+
+//Give a live field or key an expiry, or clear it with None
+pub fn expire(key: &str, field: Option<&str>, at: Option<u64>, now: u64) -> bool {
+    if !live(key, field, now) {
+        return false;
+    }
+    set_expire_at(key, field, at);
+    true
+}
+
+fn like_key(key: &str, field: &str) -> LikeKey {
+    LikeKey {
+        key: key.to_string(),
+        field: field.to_string(),
+    }
+}
+
+pub fn insert(key: String, field: String, value: Vec<u8>, now: u64) -> bool {
+    if !valid_entry(&key, &field) {
+        return false;
+    }
+    reclaim_expired(&key, &field, now);
+    let size = entry_size(&key, &field, &value);
+    let lk = LikeKey { key, field };
+    let old = put_value(&lk, value);
+    let stored_size = old.as_ref().map_or(0, |v| stored_size(&lk, v));
+    add_slot_bytes(&lk, size, stored_size);
+    let old_size = match old {
+        Some(_) => stored_size,
+        None => {
+            add_count(&lk.key, 1, 0);
+            //A legacy entry is replaced, not shadowed, so every field lives in one place
+            take_legacy(&lk.key, &lk.field).map_or(0, |v| entry_size(&lk.key, &lk.field, &v))
+        }
+    };
+    add_bytes(size, old_size);
+    true
+}
+
+fn take_legacy(key: &str, field: &str) -> Option<Vec<u8>> {
+    LEGACY.with(|legacy| {
+        let mut legacy = legacy.borrow_mut();
+        let value = legacy.get_field(&key.to_string(), &field.to_string());
+        if value.is_some() {
+            legacy.remove_field(key.to_string(), field.to_string());
+        }
+        value
+    })
+}
+
+//Add `delta` to an integer counter stored as decimal text, a missing field
+//counts as zero. `apply` returns None when the result is out of range.
+fn update_counter<T>(
+    key: String,
+    field: String,
+    apply: impl FnOnce(T) -> Option<T>,
+    now: u64,
+) -> Result<T, String>
+where
+    T: FromStr + ToString + Default + Copy,
+{
+    let current = match get(&key, &field, now) {
+        Some(bytes) => std::str::from_utf8(&bytes)
+            .ok()
+            .and_then(|text| text.parse::<T>().ok())
+            .ok_or_else(|| format!("Error: {}/{} is not an integer", key, field))?,
+        None => T::default(),
+    };
+    let next = apply(current).ok_or_else(|| format!("Error: {}/{} out of range", key, field))?;
+    if !insert(key, field, next.to_string().into_bytes(), now) {
+        return Err("Error: key or field too long".to_string());
+    }
+    Ok(next)
+}
+
+pub fn incr_by(key: String, field: String, delta: i64, now: u64) -> Result<i64, String> {
+    update_counter(key, field, |n: i64| n.checked_add(delta), now)
+}
+
+//Counter that never goes below zero
+pub fn incr_by_nat(key: String, field: String, delta: i64, now: u64) -> Result<u64, String> {
+    update_counter(key, field, |n: u64| n.checked_add_signed(delta), now)
+}
+
+//Write a field, replacing any expiry it had with `expire_at`
+pub fn set(key: String, field: String, value: Vec<u8>, expire_at: Option<u64>, now: u64) -> bool {
+    if !insert(key.clone(), field.clone(), value, now) {
+        return false;
+    }
+    set_expire_at(&key, Some(&field), expire_at);
+    true
+}
+
+pub fn get(key: &str, field: &str, now: u64) -> Option<Vec<u8>> {
+    if expired(key, field, now) {
+        return None;
+    }
+    let lk = like_key(key, field);
+    let value = LIKES.with(|likes| likes.borrow().get(&lk));
+    match value {
+        Some(v) => Some(read_value(&lk, v)),
+        None => LEGACY.with(|legacy| {
+            legacy
+                .borrow()
+                .get_field(&key.to_string(), &field.to_string())
+        }),
+    }
+}
+
+pub fn remove(key: &str, field: &str, now: u64) -> bool {
+    let live = !expired(key, field, now);
+    let removed = delete(key, field);
+    //A key without fields does not keep its expiry
+    if stored_count(key) == 0 {
+        set_expire_at(key, None, None);
+    }
+    live && removed
+}
+
+//Drop an entry and its expiry whether or not it is expired
+fn delete(key: &str, field: &str) -> bool {
+    let lk = like_key(key, field);
+    let removed = remove_value(&lk);
+    let legacy_removed = take_legacy(key, field);
+
+    let mut freed = 0;
+    if let Some(v) = &removed {
+        let size = stored_size(&lk, v);
+        freed += size;
+        add_count(key, 0, 1);
+        add_slot_bytes(&lk, 0, size);
+    }
+    if let Some(v) = &legacy_removed {
+        freed += entry_size(key, field, v);
+    }
+    add_bytes(0, freed);
+    set_expire_at(key, Some(field), None);
+    removed.is_some() || legacy_removed.is_some()
+}
+
+//Expired data is reclaimed before it is written again
+fn reclaim_expired(key: &str, field: &str, now: u64) {
+    if key_expired(key, now) {
+        purge(key);
+    } else if field_expired(key, field, now) {
+        delete(key, field);
+    }
+}
+
+//Drop up to `limit` fields of a key, returns true once the key is gone and
+//its expiry cleared
+fn purge_key(key: &str, limit: usize) -> bool {
+    let start = Bound::Included(like_key(key, ""));
+    let fields: Vec<String> = LIKES.with(|likes| {
+        likes
+            .borrow()
+            .range((start, Bound::Unbounded))
+            .take_while(|(k, _)| k.key == key)
+            .take(limit)
+            .map(|(k, _)| k.field)
+            .collect()
+    });
+    let done = fields.len() < limit;
+    for f in fields {
+        delete(key, &f);
+    }
+    if !done {
+        return false;
+    }
+
+    let legacy = LEGACY.with(|legacy| legacy.borrow_mut().hset.remove(key));
+    if let Some(fields) = legacy {
+        add_bytes(0, fields_size(key, &fields));
+        for f in fields.field.keys() {
+            set_expire_at(key, Some(f), None);
+        }
+    }
+    set_expire_at(key, None, None);
+    true
+}
+
+//Reclaim entries whose expiry passed, handling at most `budget` entries.
+//What is left over is picked up by the next round.
+pub fn sweep_expired(budget: usize, now: u64) {
+    let mut budget = budget;
+    while budget > 0 {
+        let next = DEADLINES.with(|deadlines| deadlines.borrow().iter().next());
+        let target = match next {
+            Some((deadline, _)) if deadline.at <= now => deadline.target,
+            _ => return,
+        };
+        match target.field {
+            Some(f) => {
+                delete(&target.key, &f);
+                if stored_count(&target.key) == 0 {
+                    set_expire_at(&target.key, None, None);
+                }
+                budget -= 1;
+            }
+            None => {
+                let fields = stored_count(&target.key) as usize;
+                if !purge_key(&target.key, budget) {
+                    return;
+                }
+                budget = budget.saturating_sub(fields.max(1));
+            }
+        }
+    }
+}
+
+//Fields of `key` starting with `prefix` and ordered after `after`, at most
+//`limit` of them. Returns the entries and the cursor for the next page.
+pub fn scan(
+    key: &str,
+    prefix: &str,
+    after: Option<&str>,
+    limit: usize,
+    now: u64,
+) -> (Vec<(String, Vec<u8>)>, Option<String>) {
+    let start = match after {
+        Some(cursor) if cursor >= prefix => Bound::Excluded(like_key(key, cursor)),
+        _ => Bound::Included(like_key(key, prefix)),
+    };
+    if key_expired(key, now) {
+        return (vec![], None);
+    }
+    let in_page =
+        |field: &str| field.starts_with(prefix) && after.is_none_or(|cursor| field > cursor);
+
+    let mut page: BTreeMap<String, Vec<u8>> = LIKES.with(|likes| {
+        likes
+            .borrow()
+            .range((start, Bound::Unbounded))
+            .take_while(|(k, _)| k.key == key && k.field.starts_with(prefix))
+            .filter(|(k, _)| !field_expired(key, &k.field, now))
+            .take(limit.saturating_add(1))
+            .map(|(k, v)| {
+                let value = read_value(&k, v);
+                (k.field, value)
+            })
+            .collect()
+    });
+
+    //Legacy entries shadowed by a newer write in LIKES are skipped
+    LEGACY.with(|legacy| {
+        if let Some(fields) = legacy.borrow().get_key(&key.to_string()) {
+            LIKES.with(|likes| {
+                let likes = likes.borrow();
+                for (f, v) in fields.field.iter() {
+                    if in_page(f)
+                        && !likes.contains_key(&like_key(key, f))
+                        && !field_expired(key, f, now)
+                    {
+                        page.insert(f.clone(), v.clone());
+                    }
+                }
+            });
+        }
+    });
+
+    let mut entries: Vec<(String, Vec<u8>)> =
+        page.into_iter().take(limit.saturating_add(1)).collect();
+    let next = if entries.len() > limit {
+        entries.truncate(limit);
+        entries.last().map(|(f, _)| f.clone())
+    } else {
+        None
+    };
+    (entries, next)
+}
+
+//Up to `limit` entries after `after` whose key passes `filter`, looking at no
+//more than `budget` entries and stopping before the values would go over
+//BATCH_BYTES, a single larger value is returned on its own. Returns them
+//with the last entry looked at and whether the end of the map was reached.
+pub fn range_batch(
+    after: Option<&LikeKey>,
+    filter: impl Fn(&str) -> bool,
+    limit: usize,
+    budget: usize,
+) -> (Vec<Entry>, Option<LikeKey>, bool) {
+    let start = match after {
+        Some(k) => Bound::Excluded(k.clone()),
+        None => Bound::Unbounded,
+    };
+    LIKES.with(|likes| {
+        let likes = likes.borrow();
+        let mut entries = vec![];
+        let mut bytes = 0;
+        let mut last = after.cloned();
+        for (seen, (k, v)) in likes.range((start, Bound::Unbounded)).enumerate() {
+            if seen == budget || entries.len() == limit {
+                return (entries, last, false);
+            }
+            if filter(&k.key) {
+                if !entries.is_empty() && bytes + v.len() > BATCH_BYTES {
+                    return (entries, last, false);
+                }
+                bytes += v.len();
+                entries.push((k.clone(), read_value(&k, v)));
+            }
+            last = Some(k);
+        }
+        (entries, last, true)
+    })
+}
+
+//Drop a field and its expiry, expired or not
+pub fn discard(key: &str, field: &str) {
+    delete(key, field);
+    if stored_count(key) == 0 {
+        set_expire_at(key, None, None);
+    }
+}
+
+//Drop a whole key and its expiries
+pub fn purge(key: &str) {
+    while !purge_key(key, usize::MAX) {}
+}
+
+pub fn mark_dirty(key: &str) {
+    DIRTY.with(|dirty| dirty.borrow_mut().insert(CountKey(key.to_string()), ()));
+}
+
+//Remove and return up to `limit` dirty keys
+pub fn take_dirty(limit: usize) -> Vec<String> {
+    DIRTY.with(|dirty| {
+        let mut dirty = dirty.borrow_mut();
+        let keys: Vec<CountKey> = dirty.iter().take(limit).map(|(k, _)| k).collect();
+        for k in keys.iter() {
+            dirty.remove(k);
+        }
+        keys.into_iter().map(|k| k.0).collect()
+    })
+}
+
+//The old layout starts with a Candid header, the memory manager with "MGR"
+pub fn is_legacy_layout() -> bool {
+    if ic_cdk::api::stable::stable64_size() == 0 {
+        return false;
+    }
+    let mut magic = [0u8; 4];
+    ic_cdk::api::stable::stable64_read(0, &mut magic);
+    magic == *b"DIDL"
+}
+
+//Hand over the data of the old layout on the first restore from it, its
+//bytes are counted into the data bytes once here
+pub fn set_legacy(likes: HashSet<String, Vec<u8>>) {
+    let size = likes.hset.iter().map(|(k, v)| fields_size(k, v)).sum();
+    LEGACY.with(|legacy| *legacy.borrow_mut() = likes);
+    add_bytes(size, 0);
+}
+
+pub fn has_legacy() -> bool {
+    LEGACY.with(|legacy| legacy.borrow().len() > 0)
+}
+
+pub fn legacy_error() -> Option<String> {
+    LEGACY_ERROR.with(|error| error.borrow().clone())
+}
+
+//Move a batch of keys from the legacy set into the stable map, returns
+//whether legacy data is left. Entries written after the upgrade already
+//live in LIKES and are kept. A key or field too long for LIKES stops the
+//drain with an error, its entries stay readable in the legacy set.
+pub fn drain_legacy() -> Result<bool, String> {
+    let batch: Vec<String> = LEGACY.with(|legacy| {
+        legacy
+            .borrow()
+            .hset
+            .keys()
+            .take(LEGACY_BATCH)
+            .cloned()
+            .collect()
+    });
+
+    let mut freed = 0;
+    let mut result = Ok(());
+    for k in batch {
+        let oversized = LEGACY.with(|legacy| {
+            legacy
+                .borrow()
+                .get_key(&k)
+                .and_then(|fields| fields.field.keys().find(|f| !valid_entry(&k, f)).cloned())
+        });
+        if let Some(f) = oversized {
+            result = Err(format!(
+                "Error: legacy entry {}/{} is longer than {} bytes, legacy drain stopped",
+                k, f, MAX_KEY_SIZE
+            ));
+            break;
+        }
+
+        let fields = LEGACY.with(|legacy| legacy.borrow_mut().hset.remove(&k));
+        for (f, v) in fields.into_iter().flat_map(|fields| fields.field) {
+            let lk = LikeKey {
+                key: k.clone(),
+                field: f,
+            };
+            if LIKES.with(|likes| likes.borrow().contains_key(&lk)) {
+                freed += entry_size(&lk.key, &lk.field, &v);
+            } else {
+                add_count(&lk.key, 1, 0);
+                add_slot_bytes(&lk, entry_size(&lk.key, &lk.field, &v), 0);
+                put_value(&lk, v);
+            }
+        }
+    }
+    add_bytes(0, freed);
+    if let Err(e) = &result {
+        LEGACY_ERROR.with(|error| *error.borrow_mut() = Some(e.clone()));
+    }
+    result.map(|_| has_legacy())
+}
+
+fn write_blob(id: MemoryId, bytes: &[u8]) {
+    let mut memory = MEMORY_MANAGER.with(|m| m.borrow().get(id));
+    let mut w = Writer::new(&mut memory, 0);
+    w.write(&(bytes.len() as u32).to_le_bytes()).unwrap();
+    w.write(bytes).unwrap();
+}
+
+fn read_blob(id: MemoryId) -> Option<Vec<u8>> {
+    let memory = MEMORY_MANAGER.with(|m| m.borrow().get(id));
+    if memory.size() == 0 {
+        return None;
+    }
+    let mut buf: [u8; 4] = [0; 4];
+    memory.read(0, &mut buf);
+    let len = u32::from_le_bytes(buf);
+
+    let mut data = vec![0; len as usize];
+    memory.read(4, &mut data);
+    Some(data)
+}
+
+pub fn save<T: CandidType>(state: &T) {
+    write_blob(STATE_MEMORY, &Encode!(state).unwrap());
+    let legacy = LEGACY.with(|legacy| Encode!(&*legacy.borrow()).unwrap());
+    write_blob(LEGACY_MEMORY, &legacy);
+}
+
+pub fn load<T: CandidType + for<'de> Deserialize<'de>>() -> Option<T> {
+    //Its bytes were counted when it was first restored from the old layout
+    if let Some(bytes) = read_blob(LEGACY_MEMORY) {
+        let likes = Decode!(&bytes, HashSet<String, Vec<u8>>).unwrap();
+        LEGACY.with(|legacy| *legacy.borrow_mut() = likes);
+    }
+    read_blob(STATE_MEMORY).map(|bytes| Decode!(&bytes, T).unwrap())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn legacy(entries: &[(&str, &str, &[u8])]) -> HashSet<String, Vec<u8>> {
+        let mut likes = HashSet::new();
+        for (k, f, v) in entries {
+            likes
+                .hset
+                .entry(k.to_string())
+                .or_default()
+                .field
+                .insert(f.to_string(), v.to_vec());
+        }
+        likes
+    }
+
+    fn stored(key: &str, field: &str) -> Option<Vec<u8>> {
+        let lk = like_key(key, field);
+        LIKES
+            .with(|likes| likes.borrow().get(&lk))
+            .map(|v| read_value(&lk, v))
+    }
+
+    #[test]
+    fn like_keys_keep_the_fields_of_a_key_together() {
+        for (k, f) in [("ab", "a"), ("a", "z"), ("a", ""), ("b", "a"), ("a", "b")] {
+            put_value(&like_key(k, f), vec![]);
+        }
+        let order: Vec<(String, String)> = LIKES.with(|likes| {
+            likes
+                .borrow()
+                .iter()
+                .map(|(lk, _)| (lk.key, lk.field))
+                .collect()
+        });
+        let expected = [("a", ""), ("a", "b"), ("a", "z"), ("ab", "a"), ("b", "a")];
+        assert_eq!(order, expected.map(|(k, f)| (k.to_string(), f.to_string())));
+    }
+
+    #[test]
+    fn like_keys_round_trip_through_bytes() {
+        for lk in [like_key("a", "bc"), like_key("ab", "c"), like_key("", "")] {
+            assert!(LikeKey::from_bytes(lk.to_bytes()) == lk);
+        }
+    }
+
+    #[test]
+    fn drain_keeps_the_legacy_bytes_counted_once() {
+        set_legacy(legacy(&[
+            ("k1", "f1", &[1, 2, 3]),
+            ("k1", "f2", &[4]),
+            ("k2", "g", &[5; 10]),
+        ]));
+        assert_eq!(data_bytes(), 7 + 5 + 13);
+
+        while drain_legacy().unwrap() {}
+        assert!(!has_legacy());
+        assert_eq!(data_bytes(), 7 + 5 + 13);
+        assert_eq!(stored_count("k1"), 2);
+        assert_eq!(stored("k1", "f1"), Some(vec![1, 2, 3]));
+        assert_eq!(stored("k2", "g"), Some(vec![5; 10]));
+    }
+
+    #[test]
+    fn drain_frees_the_bytes_of_entries_already_moved() {
+        set_legacy(legacy(&[("k", "f", &[1, 2, 3])]));
+        //written after the upgrade, counted on its own
+        put_value(&like_key("k", "f"), vec![9]);
+        add_bytes(entry_size("k", "f", &[9]), 0);
+
+        drain_legacy().unwrap();
+        assert_eq!(data_bytes(), 3);
+        assert_eq!(stored("k", "f"), Some(vec![9]));
+    }
+
+    #[test]
+    fn drain_moves_long_values_in_chunks() {
+        let value = vec![7; 3 * CHUNK_SIZE + 1];
+        set_legacy(legacy(&[("k", "f", &value)]));
+
+        drain_legacy().unwrap();
+        assert_eq!(data_bytes(), 2 + value.len() as u64);
+        assert_eq!(stored("k", "f"), Some(value));
+    }
+
+    #[test]
+    fn only_short_values_are_kept_inline() {
+        for (field, len) in [("short", INLINE_SIZE), ("long", INLINE_SIZE + 1)] {
+            let lk = like_key("k", field);
+            put_value(&lk, vec![1; len]);
+            let inline = matches!(
+                LIKES.with(|likes| likes.borrow().get(&lk)),
+                Some(LikeValue::Inline(_))
+            );
+            assert_eq!(inline, len <= INLINE_SIZE);
+            assert_eq!(stored("k", field), Some(vec![1; len]));
+        }
+    }
+
+    #[test]
+    fn drain_stops_on_a_key_too_long_to_move() {
+        let long = "k".repeat(MAX_KEY_SIZE + 1);
+        set_legacy(legacy(&[(&long, "f", &[1])]));
+
+        assert!(drain_legacy().is_err());
+        assert!(has_legacy());
+        assert!(legacy_error().is_some());
+        assert_eq!(data_bytes(), long.len() as u64 + 2);
+        assert_eq!(stored_count(&long), 1);
+    }
+
+    #[test]
+    fn restoring_saved_legacy_data_does_not_count_it_again() {
+        set_legacy(legacy(&[("k", "f", &[1, 2, 3])]));
+        save(&());
+        load::<()>();
+        assert_eq!(data_bytes(), 5);
+    }
+
+    const NOW: u64 = 1_000;
+
+    fn fields(key: &str, n: usize) {
+        for i in 0..n {
+            insert(key.to_string(), format!("f{}", i), vec![1], 0);
+        }
+    }
+
+    #[test]
+    fn expired_entries_are_hidden_from_reads() {
+        fields("k", 3);
+        set_expire_at("k", Some("f1"), Some(NOW));
+        set_expire_at("k", Some("f2"), Some(NOW + 1));
+
+        assert_eq!(get("k", "f1", NOW - 1), Some(vec![1]));
+        assert_eq!(get("k", "f1", NOW), None);
+        assert_eq!(count("k", NOW - 1), 3);
+        assert_eq!(count("k", NOW), 2);
+        let fields: Vec<String> = scan("k", "", None, 10, NOW)
+            .0
+            .into_iter()
+            .map(|(f, _)| f)
+            .collect();
+        assert_eq!(fields, ["f0", "f2"]);
+
+        set_expire_at("k", None, Some(NOW));
+        assert_eq!(get("k", "f0", NOW), None);
+        assert_eq!(count("k", NOW), 0);
+        assert!(scan("k", "", None, 10, NOW).0.is_empty());
+    }
+
+    #[test]
+    fn sweep_stays_within_its_budget() {
+        fields("a", 3);
+        for i in 0..3 {
+            set_expire_at("a", Some(&format!("f{}", i)), Some(NOW));
+        }
+        fields("b", 3);
+        set_expire_at("b", None, Some(NOW));
+
+        sweep_expired(2, NOW);
+        assert_eq!(stored_count("a"), 1);
+        assert_eq!(stored_count("b"), 3);
+
+        sweep_expired(2, NOW);
+        assert_eq!(stored_count("a"), 0);
+        assert_eq!(stored_count("b"), 2);
+
+        sweep_expired(10, NOW);
+        assert_eq!(stored_count("b"), 0);
+        assert_eq!(expire_at("b", None), None);
+    }
+
+    #[test]
+    fn sweep_leaves_entries_not_expired_yet() {
+        fields("k", 2);
+        set_expire_at("k", Some("f0"), Some(NOW + 1));
+        sweep_expired(10, NOW);
+        assert_eq!(stored_count("k"), 2);
+    }
+
+    #[test]
+    fn reclaimed_entries_free_their_bytes() {
+        fields("k", 2);
+        assert_eq!(data_bytes(), 2 * 4);
+        set_expire_at("k", Some("f0"), Some(NOW));
+
+        sweep_expired(10, NOW);
+        assert_eq!(data_bytes(), 4);
+
+        set_expire_at("k", Some("f1"), Some(NOW));
+        //an expired field is reclaimed before it is written again
+        insert("k".to_string(), "f1".to_string(), vec![1, 2], NOW);
+        assert_eq!(data_bytes(), 5);
+        assert_eq!(expire_at("k", Some("f1")), None);
+    }
+
+    #[test]
+    fn range_batches_stop_before_going_over_their_bytes() {
+        let large = vec![1; BATCH_BYTES as usize];
+        insert("a".to_string(), "f".to_string(), vec![1], 0);
+        insert("b".to_string(), "f".to_string(), large.clone(), 0);
+
+        let (first, last, done) = range_batch(None, |_| true, 10, 10);
+        assert_eq!(first.len(), 1);
+        assert!(!done);
+        let (second, _, _) = range_batch(last.as_ref(), |_| true, 10, 10);
+        assert_eq!(second.len(), 1);
+        assert_eq!(second[0].1, large);
+    }
+}
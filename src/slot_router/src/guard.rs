@@ -0,0 +1,24 @@
+use std::cell::Cell;
+use std::thread::LocalKey;
+
+//Holds a `running` flag of a background job and clears it when dropped.
+//ic-cdk drops the future of a call that traps after an await, so the flag
+//is cleared on a trap too and the job can run again.
+pub struct RunGuard(&'static LocalKey<Cell<bool>>);
+
+impl RunGuard {
+    //None while the job is already running
+    pub fn try_start(flag: &'static LocalKey<Cell<bool>>) -> Option<Self> {
+        if flag.with(|running| running.replace(true)) {
+            None
+        } else {
+            Some(Self(flag))
+        }
+    }
+}
+
+impl Drop for RunGuard {
+    fn drop(&mut self) {
+        self.0.with(|running| running.set(false));
+    }
+}
@@ -0,0 +1,64 @@
+//Hash slots, slot maps, slot loads, management canister calls and the run
+//guard of background jobs shared by users_index, like_allot and likes. Keys
+//must hash the same way in every canister, so the slot of a key is only ever
+//computed here.
+mod guard;
+mod load;
+mod management;
+mod map;
+
+pub use guard::*;
+pub use load::*;
+pub use management::*;
+pub use map::*;
+
+use crc::{Algorithm, Crc};
+
+pub const SLOT_SIZE: u32 = 65536; //Hash slot size
+pub const MAX_SLOT: u32 = SLOT_SIZE - 1; //A slot map covers 0..=MAX_SLOT
+
+//Trap messages of a likes shard, like_allot matches on them to tell the
+//caller why a routed call failed
+pub const ERR_SHARD_MIGRATING: &str = "Error: slot range is migrating, retry later";
+pub const ERR_SHARD_UNAUTHORIZED: &str = "Error: caller may not write to this canister";
+
+pub fn crc16(key: &str) -> u32 {
+    const CUSTOM_ALG: Algorithm<u16> = Algorithm {
+        width: 16,
+        poly: 0x8005,
+        init: 0xffff,
+        refin: false,
+        refout: false,
+        xorout: 0x0000,
+        check: 0xaee7,
+        residue: 0x0000,
+    };
+    let crc = Crc::<u16>::new(&CUSTOM_ALG);
+    let mut digest = crc.digest();
+
+    digest.update(key.as_bytes());
+
+    digest.finalize() as u32
+}
+
+//Slot of a key. Keys only land in 0..MAX_SLOT, the modulus predates the
+//slot map and changing it would move stored data.
+pub fn key_slot(key: &str) -> u32 {
+    crc16(key) % (SLOT_SIZE - 1)
+}
+
+//Slot ranges of `parts` shards covering 0..=MAX_SLOT, the first
+//`SLOT_SIZE % parts` ranges take one extra slot
+pub fn split_slots(parts: u32) -> Vec<(u32, u32)> {
+    let size = SLOT_SIZE / parts.max(1);
+    let extra = SLOT_SIZE % parts.max(1);
+    let mut start = 0;
+    (0..parts)
+        .map(|i| {
+            let len = size + (i < extra) as u32;
+            let range = (start, start + len - 1);
+            start += len;
+            range
+        })
+        .collect()
+}
@@ -0,0 +1,89 @@
+use candid::CandidType;
+use serde::{Deserialize, Serialize};
+
+//Keys and bytes a shard holds in one slot
+#[derive(Clone, Copy, Debug, Default, PartialEq, CandidType, Serialize, Deserialize)]
+pub struct SlotLoad {
+    pub slot: u32,
+    pub keys: u64,
+    pub bytes: u64,
+}
+
+//Split start..=end into `parts` ranges holding about the same bytes. Ranges
+//get equal slot counts when the loads hold no bytes. `parts` is capped by
+//the slots in the range, every range keeps at least one slot.
+pub fn balanced_ranges(start: u32, end: u32, loads: &[SlotLoad], parts: u32) -> Vec<(u32, u32)> {
+    let width = (end - start) as u64 + 1;
+    let parts = (parts as u64).clamp(1, width);
+    let mut loads: Vec<&SlotLoad> = loads
+        .iter()
+        .filter(|l| l.slot >= start && l.slot <= end)
+        .collect();
+    loads.sort_by_key(|l| l.slot);
+    let total: u128 = loads.iter().map(|l| l.bytes as u128).sum();
+
+    //first slot of every range after the first
+    let mut cuts: Vec<u64> = vec![];
+    if total == 0 {
+        cuts = (1..parts)
+            .map(|i| start as u64 + width * i / parts)
+            .collect();
+    } else {
+        //a share is reached inside a slot, the cut goes on the closer side of it
+        let parts = parts as u128;
+        let mut acc: u128 = 0;
+        for l in loads {
+            let before = acc * parts;
+            acc += l.bytes as u128;
+            while (cuts.len() as u128) < parts - 1
+                && acc * parts >= total * (cuts.len() as u128 + 1)
+            {
+                let share = total * (cuts.len() as u128 + 1);
+                let cut = if share - before.min(share) < acc * parts - share {
+                    l.slot
+                } else {
+                    l.slot + 1
+                };
+                cuts.push(cut as u64);
+            }
+        }
+    }
+
+    //A heavy slot can not be split, neighbouring cuts are pushed apart
+    let mut prev = start as u64;
+    for (i, cut) in cuts.iter_mut().enumerate() {
+        let last = end as u64 - (parts - 2 - i as u64);
+        *cut = (*cut).max(prev + 1).min(last);
+        prev = *cut;
+    }
+
+    let mut ranges = vec![];
+    let mut from = start;
+    for cut in cuts {
+        ranges.push((from, cut as u32 - 1));
+        from = cut as u32;
+    }
+    ranges.push((from, end));
+    ranges
+}
+
+//First slot of the upper part of start..=end holding about `bytes`. The
+//lower part keeps at least one slot, so start must be below end.
+pub fn upper_cut(start: u32, end: u32, loads: &[SlotLoad], bytes: u64) -> u32 {
+    let mut loads: Vec<&SlotLoad> = loads
+        .iter()
+        .filter(|l| l.slot > start && l.slot <= end)
+        .collect();
+    loads.sort_by_key(|l| std::cmp::Reverse(l.slot));
+
+    let mut cut = end;
+    let mut acc: u64 = 0;
+    for l in loads {
+        if acc >= bytes {
+            break;
+        }
+        acc = acc.saturating_add(l.bytes);
+        cut = l.slot;
+    }
+    cut.max(start + 1).min(end)
+}
@@ -0,0 +1,195 @@
+use candid::{CandidType, Nat};
+use ic_cdk::api::call::CallResult;
+use ic_cdk::export::Principal;
+use serde::Deserialize;
+
+#[derive(CandidType, Deserialize, Clone, Copy, Debug)]
+pub enum InstallMode {
+    #[serde(rename = "install")]
+    Install,
+    #[serde(rename = "reinstall")]
+    Reinstall,
+    #[serde(rename = "upgrade")]
+    Upgrade,
+}
+
+#[derive(CandidType, Clone, Deserialize, Debug)]
+pub struct CanisterIdRecord {
+    pub canister_id: Principal,
+}
+
+#[derive(CandidType, Clone, Deserialize, Debug)]
+pub enum CanisterStatusType {
+    #[serde(rename = "running")]
+    Running,
+    #[serde(rename = "stopping")]
+    Stopping,
+    #[serde(rename = "stopped")]
+    Stopped,
+}
+
+#[derive(CandidType, Clone, Deserialize, Debug)]
+pub struct CanisterStatus {
+    pub status: CanisterStatusType,
+    pub module_hash: Option<Vec<u8>>,
+    pub memory_size: Nat,
+    pub cycles: Nat,
+}
+
+#[derive(CandidType, Debug, Clone, Deserialize)]
+pub struct CreateCanisterSettings {
+    pub controllers: Option<Vec<Principal>>,
+    pub compute_allocation: Option<Nat>,
+    pub memory_allocation: Option<Nat>,
+    pub freezing_threshold: Option<Nat>,
+}
+
+#[derive(CandidType, Debug, Clone, Deserialize)]
+pub struct CreateCanisterArgs {
+    pub cycles: u64,
+    pub settings: CreateCanisterSettings,
+}
+
+impl CreateCanisterArgs {
+    //Args for a canister funded with `cycles` and controlled by this canister
+    pub fn controlled_by_self(cycles: u64) -> Self {
+        Self {
+            cycles,
+            settings: CreateCanisterSettings {
+                controllers: Some(vec![ic_cdk::api::id()]),
+                compute_allocation: None,
+                memory_allocation: None,
+                freezing_threshold: None,
+            },
+        }
+    }
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct CanisterInstall {
+    pub mode: InstallMode,
+    pub canister_id: Principal,
+    #[serde(with = "serde_bytes")]
+    pub wasm_module: Vec<u8>,
+    #[serde(with = "serde_bytes")]
+    pub arg: Vec<u8>,
+}
+
+pub async fn install_code(
+    canister_id: Principal,
+    wasm_module: &[u8],
+    arg: Vec<u8>,
+    mode: InstallMode,
+) -> Result<(), String> {
+    let install_config = CanisterInstall {
+        mode,
+        canister_id,
+        wasm_module: wasm_module.to_vec(),
+        arg,
+    };
+
+    let ret: CallResult<()> = ic_cdk::api::call::call(
+        Principal::management_canister(),
+        "install_code",
+        (install_config,),
+    )
+    .await;
+
+    ret.map_err(|(code, msg)| {
+        format!(
+            "Error: install canister {}, error {} => {}",
+            canister_id, code as u8, msg
+        )
+    })
+}
+
+pub async fn create_canister(args: CreateCanisterArgs) -> Result<Principal, String> {
+    #[derive(CandidType)]
+    struct In {
+        settings: Option<CreateCanisterSettings>,
+    }
+
+    let in_arg = In {
+        settings: Some(args.settings),
+    };
+
+    let ret: CallResult<(CanisterIdRecord,)> = ic_cdk::api::call::call_with_payment(
+        Principal::management_canister(),
+        "create_canister",
+        (in_arg,),
+        args.cycles,
+    )
+    .await;
+
+    match ret {
+        Ok(x) => Ok(x.0.canister_id),
+        Err((code, msg)) => Err(format!(
+            "Error: create canister, error {} => {}",
+            code as u8, msg
+        )),
+    }
+}
+
+pub async fn canister_status(canister_id: Principal) -> Result<CanisterStatus, String> {
+    let ret: CallResult<(CanisterStatus,)> = ic_cdk::api::call::call(
+        Principal::management_canister(),
+        "canister_status",
+        (CanisterIdRecord { canister_id },),
+    )
+    .await;
+
+    match ret {
+        Ok(x) => Ok(x.0),
+        Err((code, msg)) => Err(format!(
+            "Error: call canister status, error {} => {}",
+            code as u8, msg
+        )),
+    }
+}
+
+async fn call_with_canister_id(method: &str, canister_id: Principal) -> Result<(), String> {
+    let ret: CallResult<()> = ic_cdk::api::call::call(
+        Principal::management_canister(),
+        method,
+        (CanisterIdRecord { canister_id },),
+    )
+    .await;
+
+    ret.map_err(|(code, msg)| {
+        format!(
+            "Error: call {} of {}, error {} => {}",
+            method, canister_id, code as u8, msg
+        )
+    })
+}
+
+pub async fn start_canister(canister_id: Principal) -> Result<(), String> {
+    call_with_canister_id("start_canister", canister_id).await
+}
+
+pub async fn stop_canister(canister_id: Principal) -> Result<(), String> {
+    call_with_canister_id("stop_canister", canister_id).await
+}
+
+//Cycles left on a deleted canister are lost, send them away first
+pub async fn delete_canister(canister_id: Principal) -> Result<(), String> {
+    call_with_canister_id("delete_canister", canister_id).await
+}
+
+//Move `cycles` of the calling canister to `canister_id`
+pub async fn deposit_cycles(canister_id: Principal, cycles: u64) -> Result<(), String> {
+    let ret: CallResult<()> = ic_cdk::api::call::call_with_payment(
+        Principal::management_canister(),
+        "deposit_cycles",
+        (CanisterIdRecord { canister_id },),
+        cycles,
+    )
+    .await;
+
+    ret.map_err(|(code, msg)| {
+        format!(
+            "Error: deposit cycles to {}, error {} => {}",
+            canister_id, code as u8, msg
+        )
+    })
+}
@@ -0,0 +1,283 @@
+use candid::types::{Serializer, Type};
+use candid::CandidType;
+use ic_cdk::export::Principal;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+
+use crate::{key_slot, MAX_SLOT};
+
+//Hash slot node
+#[derive(Clone, Debug, PartialEq, CandidType, Serialize, Deserialize)]
+pub struct CanisterNodeMap {
+    pub canister_id: Principal,
+    pub start_node: u32,
+    pub end_node: u32,
+}
+
+impl CanisterNodeMap {
+    pub fn new() -> Self {
+        Self {
+            canister_id: Principal::from_slice(&[]),
+            start_node: 0,
+            end_node: 0,
+        }
+    }
+
+    pub fn contains(&self, slot: u32) -> bool {
+        slot >= self.start_node && slot <= self.end_node
+    }
+}
+
+impl Default for CanisterNodeMap {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+//Slot ranges ordered by their first slot. Ranges never overlap, so the
+//owner of a slot is the last range starting at or before it. Encoded as a
+//plain `vec CanisterNodeMap`, the form slot maps were always stored in.
+#[derive(Clone, Debug, Default, PartialEq, Deserialize)]
+#[serde(from = "Vec<CanisterNodeMap>")]
+pub struct SlotMap {
+    ranges: BTreeMap<u32, CanisterNodeMap>,
+    //first slots of the ranges each canister owns
+    owners: BTreeMap<Principal, BTreeSet<u32>>,
+}
+
+impl SlotMap {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.ranges.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ranges.is_empty()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &CanisterNodeMap> {
+        self.ranges.values()
+    }
+
+    pub fn to_vec(&self) -> Vec<CanisterNodeMap> {
+        self.iter().cloned().collect()
+    }
+
+    //Add a range, refused when it is invalid or overlaps a range in the map
+    pub fn insert(&mut self, node: CanisterNodeMap) -> Result<(), String> {
+        if !valid_range(&node) {
+            return Err(format!(
+                "Error: invalid slot range {}..={}",
+                node.start_node, node.end_node
+            ));
+        }
+        let overlap = self
+            .ranges
+            .range(..=node.end_node)
+            .next_back()
+            .filter(|(_, elem)| elem.end_node >= node.start_node);
+        if let Some((_, elem)) = overlap {
+            return Err(format!(
+                "Error: slot range {}..={} overlaps {}..={} of {}",
+                node.start_node, node.end_node, elem.start_node, elem.end_node, elem.canister_id
+            ));
+        }
+        self.owners
+            .entry(node.canister_id)
+            .or_default()
+            .insert(node.start_node);
+        self.ranges.insert(node.start_node, node);
+        Ok(())
+    }
+
+    //Remove the range starting at `start_node`
+    pub fn remove(&mut self, start_node: u32) -> Option<CanisterNodeMap> {
+        let node = self.ranges.remove(&start_node)?;
+        if let Some(starts) = self.owners.get_mut(&node.canister_id) {
+            starts.remove(&start_node);
+            if starts.is_empty() {
+                self.owners.remove(&node.canister_id);
+            }
+        }
+        Some(node)
+    }
+
+    //Range holding `slot`
+    pub fn find(&self, slot: u32) -> Option<&CanisterNodeMap> {
+        self.ranges
+            .range(..=slot)
+            .next_back()
+            .map(|(_, elem)| elem)
+            .filter(|elem| elem.contains(slot))
+    }
+
+    //Shard owning `key`, an error when the map does not cover its slot
+    pub fn lookup(&self, key: &str) -> Result<Principal, String> {
+        let slot = key_slot(key);
+        self.find(slot)
+            .map(|elem| elem.canister_id)
+            .ok_or_else(|| format!("Error: no canister for slot {}", slot))
+    }
+
+    //Join the adjacent ranges of a canister into one
+    pub fn coalesce(&mut self, canister_id: &Principal) {
+        let starts: Vec<u32> = self
+            .owners
+            .get(canister_id)
+            .map(|starts| starts.iter().copied().collect())
+            .unwrap_or_default();
+        for start in starts.into_iter().rev() {
+            let next = self
+                .ranges
+                .get(&start)
+                .and_then(|elem| elem.end_node.checked_add(1))
+                .and_then(|next| self.ranges.get(&next))
+                .filter(|next| next.canister_id == *canister_id)
+                .map(|next| next.start_node);
+            if let Some(next) = next {
+                let next = self.remove(next).expect("range just found");
+                if let Some(elem) = self.ranges.get_mut(&start) {
+                    elem.end_node = next.end_node;
+                }
+            }
+        }
+    }
+
+    pub fn contains_canister(&self, canister_id: &Principal) -> bool {
+        self.owners.contains_key(canister_id)
+    }
+
+    pub fn ranges_of<'a>(
+        &'a self,
+        canister_id: &Principal,
+    ) -> impl Iterator<Item = &'a CanisterNodeMap> + 'a {
+        self.owners
+            .get(canister_id)
+            .into_iter()
+            .flatten()
+            .filter_map(move |start| self.ranges.get(start))
+    }
+}
+
+//Maps stored before inserts were checked may have gaps or overlaps, they
+//are repaired keeping the routing they had
+impl From<Vec<CanisterNodeMap>> for SlotMap {
+    fn from(slot_list: Vec<CanisterNodeMap>) -> Self {
+        let slot_list = if check_slot_map(&slot_list).is_ok() {
+            slot_list
+        } else {
+            repaired_slot_map(&slot_list)
+        };
+        let mut map = SlotMap::new();
+        for node in slot_list {
+            map.insert(node).expect("repaired slot map has no overlaps");
+        }
+        map
+    }
+}
+
+impl CandidType for SlotMap {
+    fn _ty() -> Type {
+        Vec::<CanisterNodeMap>::ty()
+    }
+
+    fn idl_serialize<S: Serializer>(&self, serializer: S) -> Result<(), S::Error> {
+        self.to_vec().idl_serialize(serializer)
+    }
+}
+
+impl Serialize for SlotMap {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(self.iter())
+    }
+}
+
+#[derive(Debug, CandidType, Deserialize, Default)]
+pub struct SlotMapReport {
+    //slot ranges no canister owns
+    pub gaps: Vec<(u32, u32)>,
+    //slot ranges more than one canister owns
+    pub overlaps: Vec<(u32, u32)>,
+    //entries with start after end or past MAX_SLOT
+    pub invalid: Vec<CanisterNodeMap>,
+}
+
+impl SlotMapReport {
+    pub fn is_ok(&self) -> bool {
+        self.gaps.is_empty() && self.overlaps.is_empty() && self.invalid.is_empty()
+    }
+}
+
+fn valid_range(elem: &CanisterNodeMap) -> bool {
+    elem.start_node <= elem.end_node && elem.end_node <= MAX_SLOT
+}
+
+pub fn check_slot_map(slot_list: &[CanisterNodeMap]) -> SlotMapReport {
+    let mut report = SlotMapReport::default();
+    let mut ranges: Vec<(u32, u32)> = vec![];
+    for elem in slot_list {
+        if valid_range(elem) {
+            ranges.push((elem.start_node, elem.end_node));
+        } else {
+            report.invalid.push(elem.clone());
+        }
+    }
+    ranges.sort();
+
+    //first slot not covered by the ranges seen so far
+    let mut next: u32 = 0;
+    for (start, end) in ranges {
+        if start > next {
+            report.gaps.push((next, start - 1));
+        } else if start < next {
+            report.overlaps.push((start, end.min(next - 1)));
+        }
+        next = next.max(end + 1);
+    }
+    if next <= MAX_SLOT {
+        report.gaps.push((next, MAX_SLOT));
+    }
+    report
+}
+
+//Rebuild the map without gaps or overlaps. Every slot stays on the shard the
+//old lookup picked, the last matching entry, so no data has to move. Slots
+//no entry covered never held data and join the range before them.
+pub fn repaired_slot_map(slot_list: &[CanisterNodeMap]) -> Vec<CanisterNodeMap> {
+    let valid: Vec<&CanisterNodeMap> = slot_list.iter().filter(|e| valid_range(e)).collect();
+
+    let mut bounds: Vec<u32> = valid
+        .iter()
+        .flat_map(|e| [e.start_node, e.end_node + 1])
+        .filter(|b| *b <= MAX_SLOT)
+        .collect();
+    bounds.sort();
+    bounds.dedup();
+
+    let mut map: Vec<CanisterNodeMap> = vec![];
+    for (i, start) in bounds.iter().enumerate() {
+        let end = bounds.get(i + 1).map_or(MAX_SLOT, |next| next - 1);
+        let owner = valid
+            .iter()
+            .rev()
+            .find(|e| e.contains(*start))
+            .map(|e| e.canister_id);
+        match (owner, map.last_mut()) {
+            (Some(id), Some(last)) if last.canister_id == id => last.end_node = end,
+            (None, Some(last)) => last.end_node = end,
+            (Some(id), _) => map.push(CanisterNodeMap {
+                canister_id: id,
+                start_node: *start,
+                end_node: end,
+            }),
+            (None, None) => {}
+        }
+    }
+    if let Some(first) = map.first_mut() {
+        first.start_node = 0;
+    }
+    map
+}
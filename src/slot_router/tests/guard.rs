@@ -0,0 +1,25 @@
+use slot_router::RunGuard;
+use std::cell::Cell;
+
+thread_local! {
+    static RUNNING: Cell<bool> = const { Cell::new(false) };
+}
+
+#[test]
+fn second_start_is_refused_until_the_guard_drops() {
+    let guard = RunGuard::try_start(&RUNNING).expect("not running yet");
+    assert!(RunGuard::try_start(&RUNNING).is_none());
+    drop(guard);
+    assert!(!RUNNING.with(|running| running.get()));
+    assert!(RunGuard::try_start(&RUNNING).is_some());
+}
+
+#[test]
+fn a_panic_clears_the_flag() {
+    let result = std::panic::catch_unwind(|| {
+        let _running = RunGuard::try_start(&RUNNING).expect("not running yet");
+        panic!("job failed");
+    });
+    assert!(result.is_err());
+    assert!(!RUNNING.with(|running| running.get()));
+}
@@ -0,0 +1,95 @@
+use proptest::prelude::*;
+use slot_router::*;
+
+fn loads(start: u32, end: u32) -> impl Strategy<Value = Vec<SlotLoad>> {
+    prop::collection::btree_map(start..=end, 0u64..1_000_000, 0..64).prop_map(|m| {
+        m.into_iter()
+            .map(|(slot, bytes)| SlotLoad {
+                slot,
+                keys: 1,
+                bytes,
+            })
+            .collect()
+    })
+}
+
+fn range_and_loads() -> impl Strategy<Value = (u32, u32, Vec<SlotLoad>)> {
+    (0u32..MAX_SLOT, 1u32..2000)
+        .prop_map(|(start, width)| (start, (start + width).min(MAX_SLOT)))
+        .prop_flat_map(|(start, end)| (Just(start), Just(end), loads(start, end)))
+}
+
+fn bytes_in(loads: &[SlotLoad], start: u32, end: u32) -> u64 {
+    loads
+        .iter()
+        .filter(|l| l.slot >= start && l.slot <= end)
+        .map(|l| l.bytes)
+        .sum()
+}
+
+proptest! {
+    #[test]
+    fn balanced_ranges_tile_the_range((start, end, loads) in range_and_loads(), parts in 1u32..16) {
+        let ranges = balanced_ranges(start, end, &loads, parts);
+        prop_assert_eq!(ranges.len() as u32, parts.min(end - start + 1));
+        prop_assert_eq!(ranges[0].0, start);
+        prop_assert_eq!(ranges.last().unwrap().1, end);
+        for pair in ranges.windows(2) {
+            prop_assert!(pair[0].0 <= pair[0].1);
+            prop_assert_eq!(pair[0].1 + 1, pair[1].0);
+        }
+    }
+
+    #[test]
+    fn balanced_ranges_balance_bytes((start, end, loads) in range_and_loads()) {
+        //two parts differ by at most the heaviest slot
+        let ranges = balanced_ranges(start, end, &loads, 2);
+        let heaviest = loads.iter().map(|l| l.bytes).max().unwrap_or(0);
+        let low = bytes_in(&loads, ranges[0].0, ranges[0].1);
+        let high = bytes_in(&loads, ranges[1].0, ranges[1].1);
+        prop_assert!(low.abs_diff(high) <= heaviest);
+    }
+
+    #[test]
+    fn upper_cut_moves_about_the_bytes((start, end, loads) in range_and_loads(), want in 0u64..10_000_000) {
+        let cut = upper_cut(start, end, &loads, want);
+        prop_assert!(cut > start && cut <= end);
+        //one slot less would have moved too little
+        let moved = bytes_in(&loads, cut, end);
+        let without_cut = bytes_in(&loads, cut + 1, end);
+        prop_assert!(moved >= want || cut == start + 1 || without_cut < want);
+        prop_assert!(without_cut < want || cut == end);
+    }
+}
+
+#[test]
+fn balanced_ranges_without_loads_split_evenly() {
+    assert_eq!(
+        balanced_ranges(0, MAX_SLOT, &[], 2),
+        vec![(0, 32767), (32768, MAX_SLOT)]
+    );
+    assert_eq!(
+        balanced_ranges(10, 12, &[], 5),
+        vec![(10, 10), (11, 11), (12, 12)]
+    );
+}
+
+#[test]
+fn balanced_ranges_follow_a_hot_slot() {
+    let loads = [
+        SlotLoad {
+            slot: 100,
+            keys: 1,
+            bytes: 10,
+        },
+        SlotLoad {
+            slot: 60000,
+            keys: 1,
+            bytes: 10,
+        },
+    ];
+    assert_eq!(
+        balanced_ranges(0, MAX_SLOT, &loads, 2),
+        vec![(0, 100), (101, MAX_SLOT)]
+    );
+}
@@ -0,0 +1,206 @@
+use candid::Principal;
+use crc::{Algorithm, Crc};
+use proptest::prelude::*;
+use slot_router::*;
+
+//The slot formula likes and like_allot each carried before sharing this
+//crate. Stored data was placed with it, so it must never change.
+fn legacy_key_slot(key: &str) -> u32 {
+    const CUSTOM_ALG: Algorithm<u16> = Algorithm {
+        width: 16,
+        poly: 0x8005,
+        init: 0xffff,
+        refin: false,
+        refout: false,
+        xorout: 0x0000,
+        check: 0xaee7,
+        residue: 0x0000,
+    };
+    let crc = Crc::<u16>::new(&CUSTOM_ALG);
+    let mut digest = crc.digest();
+    digest.update(key.as_bytes());
+    digest.finalize() as u32 % (65536 - 1)
+}
+
+fn canister(id: u8) -> Principal {
+    Principal::from_slice(&[id])
+}
+
+//Owner the pre-repair lookup picked, the last matching entry
+fn last_owner(slot_list: &[CanisterNodeMap], slot: u32) -> Option<Principal> {
+    slot_list
+        .iter()
+        .rev()
+        .find(|e| e.start_node <= e.end_node && e.end_node <= MAX_SLOT && e.contains(slot))
+        .map(|e| e.canister_id)
+}
+
+fn node_map() -> impl Strategy<Value = CanisterNodeMap> {
+    (0u8..8, 0u32..=MAX_SLOT + 10, 0u32..=MAX_SLOT + 10).prop_map(|(id, a, b)| CanisterNodeMap {
+        canister_id: canister(id),
+        start_node: a,
+        end_node: b,
+    })
+}
+
+#[test]
+fn crc16_matches_algorithm_check() {
+    assert_eq!(crc16("123456789"), 0xaee7);
+}
+
+proptest! {
+    #[test]
+    fn key_slot_matches_legacy(key in ".*") {
+        prop_assert_eq!(key_slot(&key), legacy_key_slot(&key));
+    }
+
+    #[test]
+    fn key_slot_in_range(key in any::<Vec<u8>>().prop_map(|b| String::from_utf8_lossy(&b).into_owned())) {
+        prop_assert!(key_slot(&key) < MAX_SLOT);
+    }
+
+    #[test]
+    fn split_slots_covers_every_slot(parts in 1u32..=SLOT_SIZE) {
+        let ranges = split_slots(parts);
+        prop_assert_eq!(ranges.len() as u32, parts);
+        let map: Vec<CanisterNodeMap> = ranges
+            .iter()
+            .enumerate()
+            .map(|(i, (start, end))| CanisterNodeMap {
+                canister_id: canister(i as u8),
+                start_node: *start,
+                end_node: *end,
+            })
+            .collect();
+        prop_assert!(check_slot_map(&map).is_ok());
+        let sizes: Vec<u32> = ranges.iter().map(|(s, e)| e - s + 1).collect();
+        prop_assert!(sizes.iter().max().unwrap() - sizes.iter().min().unwrap() <= 1);
+    }
+
+    #[test]
+    fn repaired_map_is_valid_and_keeps_routing(
+        slot_list in prop::collection::vec(node_map(), 1..12),
+        probes in prop::collection::vec(0u32..=MAX_SLOT, 64),
+    ) {
+        let repaired = repaired_slot_map(&slot_list);
+        if repaired.is_empty() {
+            //only invalid entries, nothing routed before either
+            prop_assert!(probes.iter().all(|s| last_owner(&slot_list, *s).is_none()));
+            return Ok(());
+        }
+        prop_assert!(check_slot_map(&repaired).is_ok());
+        for slot in probes {
+            if let Some(owner) = last_owner(&slot_list, slot) {
+                prop_assert_eq!(last_owner(&repaired, slot), Some(owner));
+            }
+        }
+    }
+
+    #[test]
+    fn lookup_agrees_with_key_slot(key in ".*", parts in 1u32..64) {
+        let map: SlotMap = split_slots(parts)
+            .iter()
+            .enumerate()
+            .map(|(i, (start, end))| CanisterNodeMap {
+                canister_id: canister(i as u8),
+                start_node: *start,
+                end_node: *end,
+            })
+            .collect::<Vec<_>>()
+            .into();
+        let owner = map.lookup(&key).unwrap();
+        let slot = legacy_key_slot(&key);
+        prop_assert!(map.iter().any(|e| e.canister_id == owner && e.contains(slot)));
+    }
+
+    #[test]
+    fn slot_map_find_matches_linear_scan(
+        slot_list in prop::collection::vec(node_map(), 1..12),
+        probes in prop::collection::vec(0u32..=MAX_SLOT, 64),
+    ) {
+        let mut map = SlotMap::new();
+        let mut accepted: Vec<CanisterNodeMap> = vec![];
+        for node in slot_list {
+            let overlaps = accepted
+                .iter()
+                .any(|e| e.start_node <= node.end_node && node.start_node <= e.end_node);
+            let valid = node.start_node <= node.end_node && node.end_node <= MAX_SLOT;
+            prop_assert_eq!(map.insert(node.clone()).is_ok(), valid && !overlaps);
+            if valid && !overlaps {
+                accepted.push(node);
+            }
+        }
+        prop_assert_eq!(map.len(), accepted.len());
+        for slot in probes {
+            let scanned = accepted.iter().find(|e| e.contains(slot));
+            prop_assert_eq!(map.find(slot), scanned);
+        }
+        for e in &accepted {
+            prop_assert!(map.contains_canister(&e.canister_id));
+            prop_assert!(map.ranges_of(&e.canister_id).any(|r| r == e));
+        }
+    }
+
+    #[test]
+    fn slot_map_candid_round_trip(slot_list in prop::collection::vec(node_map(), 0..12)) {
+        //decoding a stored list repairs it the same way repaired_slot_map does
+        let bytes = candid::encode_one(&slot_list).unwrap();
+        let map: SlotMap = candid::decode_one(&bytes).unwrap();
+        if !check_slot_map(&slot_list).is_ok() {
+            prop_assert_eq!(map.to_vec(), repaired_slot_map(&slot_list));
+        }
+        let again: SlotMap = candid::decode_one(&candid::encode_one(&map).unwrap()).unwrap();
+        prop_assert_eq!(again, map);
+    }
+}
+
+#[test]
+fn slot_map_remove_updates_owner_index() {
+    let mut map = SlotMap::new();
+    map.insert(CanisterNodeMap {
+        canister_id: canister(1),
+        start_node: 0,
+        end_node: 99,
+    })
+    .unwrap();
+    map.insert(CanisterNodeMap {
+        canister_id: canister(2),
+        start_node: 100,
+        end_node: MAX_SLOT,
+    })
+    .unwrap();
+    assert!(map
+        .insert(CanisterNodeMap {
+            canister_id: canister(3),
+            start_node: 50,
+            end_node: 150,
+        })
+        .is_err());
+
+    assert_eq!(map.remove(100).map(|e| e.canister_id), Some(canister(2)));
+    assert!(!map.contains_canister(&canister(2)));
+    assert!(map.find(100).is_none());
+    assert_eq!(map.find(99).map(|e| e.canister_id), Some(canister(1)));
+}
+
+#[test]
+fn slot_map_coalesce_joins_adjacent_ranges() {
+    let mut map = SlotMap::new();
+    for (id, start, end) in [(1, 0, 9), (1, 10, 19), (2, 20, 29), (1, 30, MAX_SLOT)] {
+        map.insert(CanisterNodeMap {
+            canister_id: canister(id),
+            start_node: start,
+            end_node: end,
+        })
+        .unwrap();
+    }
+    map.coalesce(&canister(1));
+
+    let ranges: Vec<(u32, u32)> = map
+        .ranges_of(&canister(1))
+        .map(|e| (e.start_node, e.end_node))
+        .collect();
+    assert_eq!(ranges, vec![(0, 19), (30, MAX_SLOT)]);
+    assert_eq!(map.len(), 3);
+    assert!(check_slot_map(&map.to_vec()).is_ok());
+}
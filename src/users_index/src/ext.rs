@@ -0,0 +1,46 @@
+// This is an experimental feature to generate Rust binding from Candid.
+// You may want to manually adjust some of the types.
+use crc::{Crc, CRC_32_ISO_HDLC};
+use ic_cdk::api::call::CallResult;
+use ic_cdk::export::candid::{self, CandidType, Deserialize};
+use ic_cdk::export::Principal;
+use sha2::{Digest, Sha224};
+
+pub type TokenIndex = u32;
+
+#[derive(CandidType, Deserialize)]
+pub enum CommonError {
+    InvalidToken(String),
+    Other(String),
+}
+
+#[derive(CandidType, Deserialize)]
+pub enum TokensResult {
+    #[serde(rename = "ok")]
+    Ok(Vec<TokenIndex>),
+    #[serde(rename = "err")]
+    Err(CommonError),
+}
+
+pub struct ExtService(pub candid::Principal);
+impl ExtService {
+    pub async fn tokens(&self, arg0: String) -> CallResult<(TokensResult,)> {
+        ic_cdk::call(self.0, "tokens", (arg0,)).await
+    }
+}
+
+// default (zero subaccount) account identifier of a principal, hex encoded
+pub fn account_identifier(user: &Principal) -> String {
+    let mut hasher = Sha224::new();
+    hasher.update(b"\x0Aaccount-id");
+    hasher.update(user.as_slice());
+    hasher.update([0u8; 32]);
+    let hash = hasher.finalize();
+
+    let crc = Crc::<u32>::new(&CRC_32_ISO_HDLC).checksum(&hash);
+    let mut out = String::with_capacity(64);
+    for b in crc.to_be_bytes().iter().chain(hash.iter()) {
+        out.push_str(&format!("{:02x}", b));
+    }
+    out
+}
@@ -0,0 +1,104 @@
+use candid::{CandidType, Nat};
+use ic_cdk::export::Principal;
+use serde::Deserialize;
+use std::cell::RefCell;
+use std::time::Duration;
+
+use crate::install::*;
+use crate::state::*;
+use crate::user::UserService;
+
+// how often the fleet status cache is rebuilt
+pub const FLEET_REFRESH_INTERVAL: Duration = Duration::from_secs(300);
+
+thread_local! {
+    static FLEET: RefCell<FleetStatus> = RefCell::new(FleetStatus::default());
+}
+
+#[derive(CandidType, Deserialize, Clone)]
+pub struct CanisterHealth {
+    pub canister_id: Principal,
+    pub users: u64,
+    pub status: Option<CanisterStatusType>,
+    pub cycles: Option<Nat>,
+    pub memory: Option<Nat>,
+    pub module_hash: Option<Vec<u8>>,
+    pub retired: bool,
+    pub last_upgrade: u64,
+    pub last_error: Option<String>,
+    pub last_error_at: u64,
+}
+
+#[derive(CandidType, Deserialize, Clone, Default)]
+pub struct FleetStatus {
+    pub updated_at: u64,
+    pub canisters: Vec<CanisterHealth>,
+    pub total_users: u64,
+    pub total_cycles: Nat,
+    pub total_memory: Nat,
+    pub index_cycles: u64,
+    pub spare_canisters: u64,
+}
+
+pub fn get_fleet_status() -> FleetStatus {
+    FLEET.with(|f| f.borrow().clone())
+}
+
+// Collects status of every users canister, then swaps in the new snapshot.
+// A canister that can not be reached is listed without cycles or memory and
+// the call error is recorded as its last error.
+pub async fn refresh_fleet_status() {
+    let mut fleet = FleetStatus::default();
+
+    for canister_id in get_canister_list() {
+        let mut health = CanisterHealth {
+            canister_id,
+            users: get_canister_user_count(canister_id),
+            status: None,
+            cycles: None,
+            memory: None,
+            module_hash: None,
+            retired: is_retired(canister_id),
+            last_upgrade: 0,
+            last_error: None,
+            last_error_at: 0,
+        };
+
+        match call_canister_status(canister_id).await {
+            Ok(status) => {
+                fleet.total_cycles += status.cycles.clone();
+                health.status = Some(status.status);
+                health.cycles = Some(status.cycles);
+                health.module_hash = status.module_hash;
+            }
+            Err(err) => record_canister_error(canister_id, &err),
+        }
+
+        match UserService(canister_id).canister_memory().await {
+            Ok((memory,)) => {
+                fleet.total_memory += memory.clone();
+                health.memory = Some(memory);
+            }
+            Err((code, msg)) => record_canister_error(
+                canister_id,
+                &format!(
+                    "An error happened during canister_memory: {}: {}",
+                    code as u8, msg
+                ),
+            ),
+        }
+
+        let events = get_canister_events(canister_id);
+        health.last_upgrade = events.last_upgrade;
+        health.last_error = events.last_error;
+        health.last_error_at = events.last_error_at;
+
+        fleet.total_users += health.users;
+        fleet.canisters.push(health);
+    }
+
+    fleet.updated_at = ic_cdk::api::time();
+    fleet.index_cycles = ic_cdk::api::canister_balance();
+    fleet.spare_canisters = get_spare_count();
+    FLEET.with(|f| *f.borrow_mut() = fleet);
+}
@@ -1,82 +1,30 @@
-use candid::{CandidType, Encode, Nat};
-use ic_cdk::api::call::CallResult;
+use candid::Encode;
 use ic_cdk::export::{candid, Principal};
 use ic_cdk::print;
-use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 
-#[derive(CandidType, Deserialize)]
-pub enum InstallMode {
-    #[serde(rename = "install")]
-    Install,
-    #[serde(rename = "reinstall")]
-    Reinstall,
-    #[serde(rename = "upgrade")]
-    Upgrade,
-}
-
-#[derive(CandidType, Clone, Deserialize, Debug)]
-pub struct CanisterIdRecord {
-    pub canister_id: Principal,
-}
+use crate::state::*;
 
-#[derive(CandidType, Debug, Clone, Deserialize)]
-pub struct CreateCanisterSettings {
-    pub controllers: Option<Vec<Principal>>,
-    pub compute_allocation: Option<Nat>,
-    pub memory_allocation: Option<Nat>,
-    pub freezing_threshold: Option<Nat>,
-}
-
-#[derive(CandidType, Deserialize, Serialize)]
-struct CanisterInstallSendArgs {
-    greet: String,
-    controllers: Vec<Principal>,
-}
+pub use slot_router::{CanisterStatus, CanisterStatusType, CreateCanisterArgs, InstallMode};
 
-#[derive(CandidType, Debug, Clone, Deserialize)]
-pub struct CreateCanisterArgs {
-    pub cycles: u64,
-    pub settings: CreateCanisterSettings,
-}
+pub const USER_DEFAULT_CYCLES: u64 = 10_000_000_000_000;
+const USER_WASM: &[u8] = std::include_bytes!("../../../.dfx/local/canisters/users/users.wasm");
 
-#[derive(CandidType, Deserialize)]
-struct CanisterInstall {
-    mode: InstallMode,
-    canister_id: Principal,
-    #[serde(with = "serde_bytes")]
-    wasm_module: Vec<u8>,
-    #[serde(with = "serde_bytes")]
-    arg: Vec<u8>,
+pub fn user_wasm_hash() -> [u8; 32] {
+    Sha256::digest(USER_WASM).into()
 }
 
-const USER_DEFAULT_CYCLES: u64 = 10_000_000_000_000;
-const USER_WASM: &[u8] = std::include_bytes!("../../../.dfx/local/canisters/users/users.wasm");
-
 pub async fn call_canister_install(
     canister_id: &Principal,
     canister_install_args: Vec<u8>,
     mode: InstallMode,
 ) -> bool {
-    let install_config: CanisterInstall = CanisterInstall {
-        mode: mode,
-        canister_id: canister_id.clone(),
-        wasm_module: USER_WASM.to_vec(),
-        arg: canister_install_args,
-    };
-
-    let ret: CallResult<()> = ic_cdk::api::call::call(
-        Principal::management_canister(),
-        "install_code",
-        (install_config,),
-    )
-    .await;
-
-    match ret {
-        Ok(_x) => true,
-        Err((code, msg)) => {
+    match slot_router::install_code(*canister_id, USER_WASM, canister_install_args, mode).await {
+        Ok(()) => true,
+        Err(e) => {
             print(format!(
-                "An error happened during the call_canister_install: {}: {}",
-                code as u8, msg
+                "An error happened during the call_canister_install: {}",
+                e
             ));
             false
         }
@@ -86,52 +34,34 @@ pub async fn call_canister_install(
 pub async fn call_canister_create(
     canister_create_args: CreateCanisterArgs,
 ) -> Result<Principal, String> {
-    #[derive(CandidType)]
-    struct In {
-        settings: Option<CreateCanisterSettings>,
-    }
-
-    let in_arg = In {
-        settings: Some(canister_create_args.settings),
-    };
-
-    let ret: CallResult<(CanisterIdRecord,)> = ic_cdk::api::call::call_with_payment(
-        Principal::management_canister(),
-        "create_canister",
-        (in_arg,),
-        canister_create_args.cycles,
-    )
-    .await;
+    slot_router::create_canister(canister_create_args).await
+}
 
-    match ret {
-        Ok(x) => Ok(x.0.canister_id),
-        Err((code, msg)) => Err(format!(
-            "Error: call create user canister, error {} => {}",
-            code as u8, msg
-        )),
-    }
+pub async fn call_canister_status(canister_id: Principal) -> Result<CanisterStatus, String> {
+    slot_router::canister_status(canister_id).await
 }
 
+// Installs a users canister left over by a failed install, or a new one. A
+// canister that fails to install is kept for the next call.
 pub async fn create_user_canister(helper: Principal) -> Result<Principal, String> {
-    let cid = ic_cdk::api::id();
-    let create_args = CreateCanisterArgs {
-        cycles: USER_DEFAULT_CYCLES,
-        settings: CreateCanisterSettings {
-            controllers: Some(vec![cid]),
-            compute_allocation: None,
-            memory_allocation: None,
-            freezing_threshold: None,
-        },
+    let (canister_id, mode) = match take_uninstalled() {
+        // an earlier install may have gone through without its reply, no
+        // user is on the canister yet so reinstalling it is safe
+        Some(canister_id) => (canister_id, InstallMode::Reinstall),
+        None => {
+            let create_args = CreateCanisterArgs::controlled_by_self(USER_DEFAULT_CYCLES);
+            let canister_id = call_canister_create(create_args).await?;
+            (canister_id, InstallMode::Install)
+        }
     };
 
-    let canister = call_canister_create(create_args.clone()).await;
-
-    match canister {
-        Ok(canister_id) => {
-            let canister_install_args = Encode!(&helper).unwrap();
-            call_canister_install(&canister_id, canister_install_args, InstallMode::Install).await;
-            Ok(canister_id)
-        }
-        Err(err) => Err(err),
+    let canister_install_args = Encode!(&helper).unwrap();
+    if !call_canister_install(&canister_id, canister_install_args, mode).await {
+        push_uninstalled(canister_id);
+        return Err(format!(
+            "Error: install user canister {} failed",
+            canister_id
+        ));
     }
+    Ok(canister_id)
 }
@@ -0,0 +1,20 @@
+// This is an experimental feature to generate Rust binding from Candid.
+// You may want to manually adjust some of the types.
+use ic_cdk::api::call::CallResult;
+use ic_cdk::export::candid::{self, CandidType, Deserialize};
+
+#[derive(CandidType, Deserialize)]
+pub struct Profile {
+    pub created: u64,
+    pub address: String,
+}
+
+pub struct LinkEthService(pub candid::Principal);
+impl LinkEthService {
+    pub async fn get_profile_by_principal(
+        &self,
+        arg0: candid::Principal,
+    ) -> CallResult<(Option<Profile>,)> {
+        ic_cdk::call(self.0, "getProfileByPrincipal", (arg0,)).await
+    }
+}
@@ -5,12 +5,22 @@ use ic_cdk_macros::*;
 use serde::Deserialize;
 
 mod dao;
+mod ext;
+mod fleet;
 mod install;
+mod linketh;
+mod migrate;
+mod policy;
+mod pool;
 mod state;
 mod user;
 
 use dao::MoraDaoService;
+use fleet::*;
 use install::*;
+use migrate::*;
+use policy::*;
+use pool::*;
 use state::*;
 use user::{PlanetMsg, UserInfo, UserService};
 
@@ -26,7 +36,13 @@ async fn update_canister(canister: Principal) -> bool {
     let helper = get_sim_helper();
 
     let canister_install_args = Encode!(&helper).unwrap();
-    return call_canister_install(&canister, canister_install_args, InstallMode::Upgrade).await;
+    let ok = call_canister_install(&canister, canister_install_args, InstallMode::Upgrade).await;
+    if ok {
+        record_canister_upgrade(canister);
+    } else {
+        record_canister_error(canister, "upgrade failed");
+    }
+    ok
 }
 
 #[query]
@@ -44,26 +60,26 @@ fn canister_count() -> u64 {
 #[query]
 #[candid_method(query)]
 fn search_canister(user: Principal) -> Option<Principal> {
-    return get_user_canister(user);
+    get_user_canister(user)
 }
 
 #[query]
 #[candid_method(query)]
 fn search_index(user: Principal) -> u128 {
-    return get_user_index(user);
+    get_user_index(user)
 }
 
 #[query(name = "canister_list")]
 #[candid_method(query)]
 fn canister_list() -> Vec<Principal> {
-    return get_canister_list();
+    get_canister_list()
 }
 
 #[query(name = "get_canister")]
 #[candid_method(query)]
 fn get_canister() -> Option<Principal> {
     let caller = ic_cdk::api::caller();
-    return get_user_canister(caller);
+    get_user_canister(caller)
 }
 
 #[query(name = "verify_canister")]
@@ -78,7 +94,16 @@ async fn login() -> Result<UserLoginResp, String> {
     let caller = ic_cdk::api::caller();
     assert_ne!(caller, Principal::anonymous());
 
-    login_call(caller).await
+    login_call(caller, None).await
+}
+
+#[update(name = "login_invite")]
+#[candid_method(update)]
+async fn login_invite(code: String) -> Result<UserLoginResp, String> {
+    let caller = ic_cdk::api::caller();
+    assert_ne!(caller, Principal::anonymous());
+
+    login_call(caller, Some(code)).await
 }
 
 #[update(name = "login_test")]
@@ -86,7 +111,130 @@ async fn login() -> Result<UserLoginResp, String> {
 async fn login_test(user: Principal) -> Result<UserLoginResp, String> {
     let caller = ic_cdk::api::caller();
     assert_eq!(caller, get_sim_owner());
-    login_call(user).await
+    login_call(user, None).await
+}
+
+#[update]
+#[candid_method(update)]
+fn set_register_policy(policy: RegisterPolicy) {
+    assert_eq!(ic_cdk::api::caller(), get_sim_owner());
+    state::set_register_policy(policy);
+}
+
+#[query]
+#[candid_method(query)]
+fn get_register_policy() -> RegisterPolicy {
+    state::get_register_policy()
+}
+
+#[query]
+#[candid_method(query)]
+fn register_quota() -> RegisterQuota {
+    get_register_quota()
+}
+
+#[update]
+#[candid_method(update)]
+fn set_allowlist(users: Vec<Principal>, allowed: bool) {
+    assert_eq!(ic_cdk::api::caller(), get_sim_owner());
+    if allowed {
+        add_allowlist(users);
+    } else {
+        remove_allowlist(users);
+    }
+}
+
+#[update]
+#[candid_method(update)]
+fn add_invite_codes(codes: Vec<String>, uses: u32) {
+    assert_eq!(ic_cdk::api::caller(), get_sim_owner());
+    state::add_invite_codes(codes, uses);
+}
+
+#[update]
+#[candid_method(update)]
+fn remove_invite_codes(codes: Vec<String>) {
+    assert_eq!(ic_cdk::api::caller(), get_sim_owner());
+    state::remove_invite_codes(codes);
+}
+
+#[update]
+#[candid_method(update)]
+fn set_cycles_floor(floor: u64) {
+    assert_eq!(ic_cdk::api::caller(), get_sim_owner());
+    state::set_cycles_floor(floor);
+}
+
+#[query]
+#[candid_method(query)]
+fn capacity() -> Capacity {
+    get_capacity()
+}
+
+#[update]
+#[candid_method(update)]
+fn set_pool_size(size: u64) {
+    assert_eq!(ic_cdk::api::caller(), get_sim_owner());
+    state::set_pool_size(size);
+    ic_cdk::spawn(refill_pool());
+}
+
+#[query]
+#[candid_method(query)]
+fn spare_canisters() -> Vec<Principal> {
+    get_spare_list()
+}
+
+#[update]
+#[candid_method(update)]
+fn retire_canister(canister: Principal, retired: bool) {
+    assert_eq!(ic_cdk::api::caller(), get_sim_owner());
+    assert!(has_canister(canister));
+    set_retired(canister, retired);
+}
+
+#[query]
+#[candid_method(query)]
+fn retired_canisters() -> Vec<Principal> {
+    get_retired_list()
+}
+
+// users canisters created but not installed, the next refill retries them
+#[query]
+#[candid_method(query)]
+fn uninstalled_canisters() -> Vec<Principal> {
+    get_uninstalled_list()
+}
+
+#[query]
+#[candid_method(query)]
+fn canister_users(canister: Principal) -> u64 {
+    get_canister_user_count(canister)
+}
+
+#[update(name = "migrate_users")]
+#[candid_method(update, rename = "migrate_users")]
+async fn migrate_users_batch(
+    from: Principal,
+    to: Principal,
+    limit: u64,
+) -> Result<MigrateReport, String> {
+    assert_eq!(ic_cdk::api::caller(), get_sim_owner());
+    migrate_users(from, to, limit).await
+}
+
+#[query]
+#[candid_method(query)]
+fn fleet_status() -> FleetStatus {
+    get_fleet_status()
+}
+
+#[update]
+#[candid_method(update)]
+async fn refresh_fleet() -> FleetStatus {
+    assert_eq!(ic_cdk::api::caller(), get_sim_owner());
+    refresh_fleet_status().await;
+    get_fleet_status()
 }
 
 #[query]
@@ -97,7 +245,7 @@ fn wallet_balance() -> u64 {
 
 #[update]
 #[candid::candid_method(update)]
-fn wallet_receive() -> () {
+fn wallet_receive() {
     let available = ic_cdk::api::call::msg_cycles_available128();
     if available > 0 {
         ic_cdk::api::call::msg_cycles_accept128(available);
@@ -134,19 +282,25 @@ async fn notify_planet_msg(msg: PlanetMsg) -> bool {
         return false;
     };
 
-    let service = UserService(canister_id.expect("unkown user canister id"));
+    let canister_id = canister_id.expect("unkown user canister id");
+    // turned away while the user moves to another canister
+    let _call = match begin_user_call(msg.user) {
+        Ok(call) => call,
+        Err(_) => return false,
+    };
+    let service = UserService(canister_id);
     match service.on_planet_msg(pid, msg).await {
-        Ok((ok,)) => {
-            return ok;
-        }
+        Ok((ok,)) => ok,
         Err((code, msg)) => {
-            print(format!(
+            let err = format!(
                 "An error happened during on_planet_msg: {}: {}",
                 code as u8, msg
-            ));
-            return false;
+            );
+            print(&err);
+            record_canister_error(canister_id, &err);
+            false
         }
-    };
+    }
 }
 
 #[pre_upgrade]
@@ -157,6 +311,8 @@ fn pre_upgrade() {
 #[post_upgrade]
 fn post_upgrade() {
     state_restore();
+    drop_stale_spares(user_wasm_hash());
+    start_timers();
 }
 
 #[init]
@@ -164,34 +320,41 @@ fn post_upgrade() {
 fn init(helper: Principal) {
     print(format!("helper id: {}", helper));
     state_set(ic_cdk::api::caller(), Some(helper));
+    drop_stale_spares(user_wasm_hash());
+    start_timers();
+}
+
+fn start_timers() {
+    ic_cdk_timers::set_timer_interval(POOL_REFILL_INTERVAL, || ic_cdk::spawn(refill_pool()));
+    ic_cdk_timers::set_timer_interval(FLEET_REFRESH_INTERVAL, || {
+        ic_cdk::spawn(refresh_fleet_status())
+    });
 }
 
-async fn login_call(caller: Principal) -> Result<UserLoginResp, String> {
+async fn login_call(caller: Principal, invite: Option<String>) -> Result<UserLoginResp, String> {
     let canister_id = match get_user_canister(caller) {
         Some(canister) => canister,
         _ => {
-            let canister = register_user(caller).await;
+            let canister = register_with_policy(caller, invite).await;
             match canister {
                 Ok(cid) => cid,
                 Err(err) => return Err(err),
             }
         }
     };
+    let _call = begin_user_call(caller)?;
     let service = UserService(canister_id);
     match service.login_proxy(caller).await {
-        Ok((userinfo,)) => {
-            return Ok(UserLoginResp {
-                canister_id: canister_id,
-                userinfo: userinfo,
-            })
-        }
+        Ok((userinfo,)) => Ok(UserLoginResp {
+            canister_id,
+            userinfo,
+        }),
         Err((code, msg)) => {
-            return Err(format!(
-                "login error during the call: {}: {}",
-                code as u8, msg
-            ));
+            let err = format!("login error during the call: {}: {}", code as u8, msg);
+            record_canister_error(canister_id, &err);
+            Err(err)
         }
-    };
+    }
 }
 
 candid::export_service!();
@@ -0,0 +1,174 @@
+use candid::CandidType;
+use ic_cdk::export::Principal;
+use ic_cdk::print;
+use serde::Deserialize;
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+
+use crate::state::*;
+use crate::user::UserService;
+
+pub const ERR_USER_MOVING: &str = "Error: user is moving to another canister, retry later";
+
+thread_local! {
+    // users with a move in flight, their calls are turned away until it ends
+    static MOVING: RefCell<HashSet<Principal>> = RefCell::new(HashSet::new());
+    // calls forwarded to a user's canister and not answered yet
+    static IN_FLIGHT: RefCell<HashMap<Principal, u32>> = RefCell::new(HashMap::new());
+}
+
+// Held while a call for a user is forwarded to its users canister. Dropped
+// when the call ends, a trap included.
+pub struct UserCall(Principal);
+
+// Fails while the user is moving, a move waits for the calls already made
+pub fn begin_user_call(user: Principal) -> Result<UserCall, String> {
+    if MOVING.with(|m| m.borrow().contains(&user)) {
+        return Err(ERR_USER_MOVING.to_string());
+    }
+    IN_FLIGHT.with(|f| *f.borrow_mut().entry(user).or_insert(0) += 1);
+    Ok(UserCall(user))
+}
+
+impl Drop for UserCall {
+    fn drop(&mut self) {
+        IN_FLIGHT.with(|f| {
+            let mut in_flight = f.borrow_mut();
+            if let Some(count) = in_flight.get_mut(&self.0) {
+                *count -= 1;
+                if *count == 0 {
+                    in_flight.remove(&self.0);
+                }
+            }
+        });
+    }
+}
+
+// Keeps a user's calls away while it moves, unfrozen when dropped
+struct Frozen(Principal);
+
+fn freeze(user: Principal) -> Result<Frozen, String> {
+    if !MOVING.with(|m| m.borrow_mut().insert(user)) {
+        return Err("Error: user is already moving".to_string());
+    }
+    let frozen = Frozen(user);
+    // a call made before the freeze may still write to the source
+    if IN_FLIGHT.with(|f| f.borrow().contains_key(&user)) {
+        return Err("Error: user has calls in flight, retry later".to_string());
+    }
+    Ok(frozen)
+}
+
+impl Drop for Frozen {
+    fn drop(&mut self) {
+        MOVING.with(|m| m.borrow_mut().remove(&self.0));
+    }
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct MigrateFailure {
+    pub user: Principal,
+    pub error: String,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct MigrateReport {
+    pub moved: Vec<Principal>,
+    pub failed: Vec<MigrateFailure>,
+    // users still served by the source canister after this batch
+    pub remaining: u64,
+}
+
+// Moves one user: freeze its calls, export from the source, import into
+// the target, flip the assignment in users_index, drop the copy left in the
+// source, then unfreeze. The source refuses the user's own writes from the
+// export on, and gives them back if the import fails.
+pub async fn migrate_user(user: Principal, to: Principal) -> Result<(), String> {
+    let _frozen = freeze(user)?;
+    let from = match get_user_canister(user) {
+        Some(canister) => canister,
+        None => return Err("Error: unknown user".to_string()),
+    };
+    if from == to {
+        return Ok(());
+    }
+
+    let data = match UserService(from).export_user(user).await {
+        Ok((Some(data),)) => data,
+        Ok((None,)) => return Err(format!("Error: user not found in {}", from)),
+        Err((code, msg)) => {
+            return Err(format!(
+                "An error happened during export_user: {}: {}",
+                code as u8, msg
+            ))
+        }
+    };
+
+    let imported = match UserService(to).import_user(data).await {
+        Ok((true,)) => Ok(()),
+        Ok((false,)) => Err(format!("Error: import rejected by {}", to)),
+        Err((code, msg)) => Err(format!(
+            "An error happened during import_user: {}: {}",
+            code as u8, msg
+        )),
+    };
+    if let Err(e) = imported {
+        if let Err((code, msg)) = UserService(from).unlock_user(user).await {
+            print(format!(
+                "An error happened during unlock_user {}: {}: {}",
+                user, code as u8, msg
+            ));
+        }
+        return Err(e);
+    }
+
+    set_user_canister(user, to);
+
+    if let Err((code, msg)) = UserService(from).remove_user(user).await {
+        print(format!(
+            "An error happened during remove_user {}: {}: {}",
+            user, code as u8, msg
+        ));
+    }
+    Ok(())
+}
+
+pub async fn migrate_users(
+    from: Principal,
+    to: Principal,
+    limit: u64,
+) -> Result<MigrateReport, String> {
+    if !has_canister(from) || !has_canister(to) {
+        return Err("Error: unknown users canister".to_string());
+    }
+    if from == to {
+        return Err("Error: source and target are the same canister".to_string());
+    }
+    if is_retired(to) {
+        return Err("Error: target canister is retired".to_string());
+    }
+
+    // users moved by another batch are skipped
+    let users: Vec<Principal> = MOVING.with(|m| {
+        let moving = m.borrow();
+        get_canister_users(from, usize::MAX)
+            .into_iter()
+            .filter(|user| !moving.contains(user))
+            .take(limit as usize)
+            .collect()
+    });
+
+    let mut report = MigrateReport {
+        moved: vec![],
+        failed: vec![],
+        remaining: 0,
+    };
+    for user in users {
+        match migrate_user(user, to).await {
+            Ok(_) => report.moved.push(user),
+            Err(error) => report.failed.push(MigrateFailure { user, error }),
+        }
+    }
+    report.remaining = get_canister_user_count(from);
+    Ok(report)
+}
@@ -0,0 +1,222 @@
+use candid::CandidType;
+use ic_cdk::export::Principal;
+use serde::Deserialize;
+
+use crate::ext::{account_identifier, ExtService, TokensResult};
+use crate::linketh::LinkEthService;
+use crate::state::*;
+
+pub const ERR_REGISTER_CLOSED: &str = "Error: registration is invite only";
+pub const ERR_REGISTER_LIMIT: &str = "Error: registration limit reached, retry later";
+pub const ERR_REGISTER_PROOF: &str = "Error: registration proof not satisfied";
+pub const ERR_CAPACITY_UNAVAILABLE: &str = "Error: capacity temporarily unavailable";
+
+const NANOS_PER_SECOND: u64 = 1_000_000_000;
+
+// cycles kept in users_index when no floor has been configured
+pub const DEFAULT_CYCLES_FLOOR: u64 = 2_000_000_000_000;
+
+// Throttles checked before a new user is assigned a users canister
+#[derive(CandidType, Deserialize, Clone, Default)]
+pub struct RegisterPolicy {
+    // length of the sign-up window, 0 disables the window limit
+    pub window_seconds: u64,
+    // max sign-ups per window, 0 means unlimited
+    pub window_limit: u64,
+    // only allowlisted users or holders of an invite code can register
+    pub invite_only: bool,
+    pub proof: Option<RegisterProof>,
+}
+
+#[derive(CandidType, Deserialize, Clone)]
+pub enum RegisterProof {
+    // user must have linked an eth address in the given linketh canister
+    EthLinked(Principal),
+    // user must hold a token of the given EXT nft canister
+    NftHolder(Principal),
+}
+
+#[derive(CandidType, Deserialize, Clone, Default)]
+pub struct RegisterWindow {
+    pub start: u64,
+    pub count: u64,
+}
+
+impl RegisterWindow {
+    pub fn end(&self, policy: &RegisterPolicy) -> u64 {
+        self.start + policy.window_seconds * NANOS_PER_SECOND
+    }
+
+    // Counts one sign-up at `now`, opening a new window once the current one ran out
+    pub fn admit(&mut self, policy: &RegisterPolicy, now: u64) -> Result<(), String> {
+        if now >= self.end(policy) {
+            *self = RegisterWindow {
+                start: now,
+                count: 0,
+            };
+        }
+        if self.count >= policy.window_limit {
+            return Err(ERR_REGISTER_LIMIT.to_string());
+        }
+        self.count += 1;
+        Ok(())
+    }
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct RegisterQuota {
+    pub used: u64,
+    pub limit: u64,
+    pub reset_at: u64,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct Capacity {
+    pub balance: u64,
+    pub floor: u64,
+    pub creation_cost: u64,
+    // cycles left above the floor after paying for one more users canister
+    pub headroom: u64,
+    // users canisters that can still be created before hitting the floor
+    pub creatable: u64,
+    // users that still fit in the current users canister
+    pub free_slots: u64,
+}
+
+impl Capacity {
+    pub fn new(balance: u64, floor: u64, creation_cost: u64, free_slots: u64) -> Self {
+        let spendable = balance.saturating_sub(floor);
+        Capacity {
+            balance,
+            floor,
+            creation_cost,
+            headroom: spendable.saturating_sub(creation_cost),
+            creatable: spendable / creation_cost,
+            free_slots,
+        }
+    }
+}
+
+pub async fn verify_register_proof(user: Principal, proof: &RegisterProof) -> Result<(), String> {
+    match proof {
+        RegisterProof::EthLinked(linketh) => {
+            let service = LinkEthService(*linketh);
+            match service.get_profile_by_principal(user).await {
+                Ok((Some(profile),)) if !profile.address.is_empty() => Ok(()),
+                Ok(_) => Err(ERR_REGISTER_PROOF.to_string()),
+                Err((code, msg)) => Err(format!(
+                    "An error happened during getProfileByPrincipal: {}: {}",
+                    code as u8, msg
+                )),
+            }
+        }
+        RegisterProof::NftHolder(nft) => {
+            let service = ExtService(*nft);
+            match service.tokens(account_identifier(&user)).await {
+                Ok((TokensResult::Ok(tokens),)) if !tokens.is_empty() => Ok(()),
+                Ok(_) => Err(ERR_REGISTER_PROOF.to_string()),
+                Err((code, msg)) => Err(format!(
+                    "An error happened during tokens: {}: {}",
+                    code as u8, msg
+                )),
+            }
+        }
+    }
+}
+
+// Runs the register policy for a new user, then assigns the users canister.
+// The sign-up slot is given back when the assignment fails.
+pub async fn register_with_policy(
+    user: Principal,
+    invite: Option<String>,
+) -> Result<Principal, String> {
+    let policy = get_register_policy();
+    if let Some(proof) = policy.proof {
+        verify_register_proof(user, &proof).await?;
+    }
+
+    let slot = take_register_slot(user, invite.as_deref())?;
+    match register_user(user).await {
+        Ok(canister_id) => Ok(canister_id),
+        Err(err) => {
+            release_register_slot(slot);
+            Err(err)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const WINDOW: u64 = 60 * NANOS_PER_SECOND;
+
+    fn policy(window_limit: u64) -> RegisterPolicy {
+        RegisterPolicy {
+            window_seconds: 60,
+            window_limit,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn window_refuses_past_the_limit() {
+        let policy = policy(2);
+        let mut window = RegisterWindow::default();
+        assert!(window.admit(&policy, WINDOW).is_ok());
+        assert!(window.admit(&policy, WINDOW + 1).is_ok());
+        assert_eq!(
+            window.admit(&policy, WINDOW + 2),
+            Err(ERR_REGISTER_LIMIT.to_string())
+        );
+        assert_eq!((window.start, window.count), (WINDOW, 2));
+    }
+
+    #[test]
+    fn window_reopens_once_it_ran_out() {
+        let policy = policy(1);
+        let mut window = RegisterWindow::default();
+        assert!(window.admit(&policy, WINDOW).is_ok());
+        assert!(window.admit(&policy, 2 * WINDOW - 1).is_err());
+        assert!(window.admit(&policy, 2 * WINDOW).is_ok());
+        assert_eq!((window.start, window.count), (2 * WINDOW, 1));
+        assert_eq!(window.end(&policy), 3 * WINDOW);
+    }
+
+    #[test]
+    fn refused_sign_up_is_not_counted() {
+        let policy = policy(1);
+        let mut window = RegisterWindow {
+            start: WINDOW,
+            count: 1,
+        };
+        assert!(window.admit(&policy, WINDOW + 1).is_err());
+        assert_eq!(window.count, 1);
+    }
+
+    const COST: u64 = 10;
+
+    #[test]
+    fn capacity_above_the_floor() {
+        let capacity = Capacity::new(125, 100, COST, 7);
+        assert_eq!(capacity.headroom, 15);
+        assert_eq!(capacity.creatable, 2);
+        assert_eq!(capacity.free_slots, 7);
+    }
+
+    #[test]
+    fn capacity_needs_the_full_creation_cost() {
+        let capacity = Capacity::new(109, 100, COST, 0);
+        assert_eq!((capacity.headroom, capacity.creatable), (0, 0));
+        let capacity = Capacity::new(110, 100, COST, 0);
+        assert_eq!((capacity.headroom, capacity.creatable), (0, 1));
+    }
+
+    #[test]
+    fn capacity_below_the_floor_saturates() {
+        let capacity = Capacity::new(50, 100, COST, 0);
+        assert_eq!((capacity.headroom, capacity.creatable), (0, 0));
+        let capacity = Capacity::new(u64::MAX, u64::MAX, COST, 0);
+        assert_eq!((capacity.headroom, capacity.creatable), (0, 0));
+    }
+}
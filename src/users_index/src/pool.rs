@@ -0,0 +1,45 @@
+use ic_cdk::print;
+use slot_router::RunGuard;
+use std::cell::Cell;
+use std::time::Duration;
+
+use crate::install::*;
+use crate::state::*;
+
+// how often the spare users canister pool is topped up
+pub const POOL_REFILL_INTERVAL: Duration = Duration::from_secs(600);
+
+thread_local! {
+    static REFILLING: Cell<bool> = const { Cell::new(false) };
+}
+
+// Creates and installs users canisters until the spare pool reaches its
+// configured size. Stops early when the cycles floor would be crossed.
+pub async fn refill_pool() {
+    // cleared when the refill ends, traps included
+    let _running = match RunGuard::try_start(&REFILLING) {
+        Some(guard) => guard,
+        None => return,
+    };
+
+    while get_spare_count() < get_pool_size() {
+        if check_cycles_reserve().is_err() {
+            break;
+        }
+        match create_user_canister(get_sim_helper()).await {
+            Ok(canister_id) => {
+                if let Err(err) = push_spare_canister(canister_id) {
+                    print(format!(
+                        "push spare canister {} error: {}",
+                        canister_id, err
+                    ));
+                    break;
+                }
+            }
+            Err(err) => {
+                print(format!("refill pool error: {}", err));
+                break;
+            }
+        }
+    }
+}
@@ -3,14 +3,18 @@ use ic_cdk::export::Principal;
 use ic_stable_structures::memory_manager::{MemoryId, MemoryManager, VirtualMemory};
 // use ic_stable_structures::reader::Reader;
 use crate::install::*;
+use crate::policy::*;
 use ic_stable_structures::writer::Writer;
 use ic_stable_structures::Memory;
 use ic_stable_structures::{BoundedStorable, DefaultMemoryImpl, StableBTreeMap, Storable, Vec};
+use sha2::{Digest, Sha256};
 use std::{borrow::Cow, cell::RefCell, vec};
 
 type VMemory = VirtualMemory<DefaultMemoryImpl>;
 
 const MAX_KEY_SIZE: u32 = 100;
+const MAX_EVENTS_SIZE: u32 = 512;
+const MAX_ERROR_LEN: usize = 256;
 // const MAX_VALUE_SIZE: u32 = 100;
 const USER_PER_SIZE: u128 = 1000;
 
@@ -30,6 +34,12 @@ struct SimState {
     owner: Principal,
     helper: Option<Principal>,
     usercount: u128,
+    register_policy: Option<RegisterPolicy>,
+    register_window: Option<RegisterWindow>,
+    cycles_floor: Option<u64>,
+    pool_size: Option<u64>,
+    // sha256 of the users wasm the spare canisters were installed with
+    spare_wasm: Option<vec::Vec<u8>>,
 }
 
 impl SimState {
@@ -38,12 +48,17 @@ impl SimState {
             owner: Principal::anonymous(),
             helper: None,
             usercount: 0,
+            register_policy: None,
+            register_window: None,
+            cycles_floor: None,
+            pool_size: None,
+            spare_wasm: None,
         }
     }
 }
 
 impl Storable for SimState {
-    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
         Cow::Owned(Encode!(self).unwrap())
     }
 
@@ -55,12 +70,12 @@ impl Storable for SimState {
 #[derive(Eq, PartialEq, PartialOrd, Ord, Clone)]
 struct StablePrincipal(Principal);
 impl Storable for StablePrincipal {
-    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
         Cow::Owned(self.0.as_slice().to_vec())
     }
 
     fn from_bytes(bytes: Cow<[u8]>) -> Self {
-        Self(Principal::from_slice(&bytes.to_vec()))
+        Self(Principal::from_slice(&bytes))
     }
 }
 
@@ -69,29 +84,109 @@ impl BoundedStorable for StablePrincipal {
     const IS_FIXED_SIZE: bool = false;
 }
 
+// A user of a users canister, ordered by canister so the users of one
+// canister sit next to each other
+#[derive(Eq, PartialEq, PartialOrd, Ord, Clone)]
+struct CanisterUser(Principal, Principal);
+impl Storable for CanisterUser {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        let canister = self.0.as_slice();
+        let mut bytes = vec![canister.len() as u8];
+        bytes.extend_from_slice(canister);
+        bytes.extend_from_slice(self.1.as_slice());
+        Cow::Owned(bytes)
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        let split = 1 + bytes[0] as usize;
+        Self(
+            Principal::from_slice(&bytes[1..split]),
+            Principal::from_slice(&bytes[split..]),
+        )
+    }
+}
+
+impl BoundedStorable for CanisterUser {
+    const MAX_SIZE: u32 = 1 + 2 * 29;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// sha256 of an invite code, the plain code is never stored
+type InviteHash = [u8; 32];
+
+#[derive(CandidType, Deserialize, Clone, Default)]
+pub struct CanisterEvents {
+    pub last_upgrade: u64,
+    pub last_error: Option<String>,
+    pub last_error_at: u64,
+}
+
+impl Storable for CanisterEvents {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(&bytes, Self).unwrap()
+    }
+}
+
+impl BoundedStorable for CanisterEvents {
+    const MAX_SIZE: u32 = MAX_EVENTS_SIZE;
+    const IS_FIXED_SIZE: bool = false;
+}
+
 struct State {
     sim_state: SimState,
     reserve_memory: VMemory,
     user_canisters: RefCell<StableBTreeMap<StablePrincipal, u128, VMemory>>,
     all_canisters: RefCell<Vec<StablePrincipal, VMemory>>,
+    allowlist: RefCell<StableBTreeMap<StablePrincipal, (), VMemory>>,
+    invite_codes: RefCell<StableBTreeMap<InviteHash, u32, VMemory>>,
+    spare_canisters: RefCell<Vec<StablePrincipal, VMemory>>,
+    // users moved away from the canister their index maps to
+    moved_users: RefCell<StableBTreeMap<StablePrincipal, StablePrincipal, VMemory>>,
+    retired_canisters: RefCell<StableBTreeMap<StablePrincipal, (), VMemory>>,
+    canister_events: RefCell<StableBTreeMap<StablePrincipal, CanisterEvents, VMemory>>,
+    // created users canisters whose install failed, reused before creating more
+    uninstalled_canisters: RefCell<StableBTreeMap<StablePrincipal, (), VMemory>>,
+    // the users served by each canister, kept with every assignment and move
+    canister_users: RefCell<StableBTreeMap<CanisterUser, (), VMemory>>,
 }
 
 impl State {
     fn new() -> Self {
-        let (m0, m1, m2) = MEMORY_MANAGER.with(|m| {
+        let (m0, m1, m2, m3, m4, m5, m6, m7, m8, m9, m10) = MEMORY_MANAGER.with(|m| {
             let manager = m.borrow();
             (
                 manager.get(MemoryId::new(0)),
                 manager.get(MemoryId::new(1)),
                 manager.get(MemoryId::new(2)),
+                manager.get(MemoryId::new(3)),
+                manager.get(MemoryId::new(4)),
+                manager.get(MemoryId::new(5)),
+                manager.get(MemoryId::new(6)),
+                manager.get(MemoryId::new(7)),
+                manager.get(MemoryId::new(8)),
+                manager.get(MemoryId::new(9)),
+                manager.get(MemoryId::new(10)),
             )
         });
         let all = Vec::init(m2);
+        let spare = Vec::init(m5);
         Self {
             sim_state: SimState::new(),
             reserve_memory: m0,
             user_canisters: RefCell::new(StableBTreeMap::init(m1)),
             all_canisters: RefCell::new(all.expect("state vec memory error")),
+            allowlist: RefCell::new(StableBTreeMap::init(m3)),
+            invite_codes: RefCell::new(StableBTreeMap::init(m4)),
+            spare_canisters: RefCell::new(spare.expect("spare vec memory error")),
+            moved_users: RefCell::new(StableBTreeMap::init(m6)),
+            retired_canisters: RefCell::new(StableBTreeMap::init(m7)),
+            canister_events: RefCell::new(StableBTreeMap::init(m8)),
+            uninstalled_canisters: RefCell::new(StableBTreeMap::init(m9)),
+            canister_users: RefCell::new(StableBTreeMap::init(m10)),
         }
     }
 
@@ -115,6 +210,25 @@ impl State {
 
         self.sim_state = SimState::from_bytes(data.to_bytes());
     }
+
+    // Fills the canister index of users assigned before it was kept
+    fn build_canister_users(&self) {
+        if !self.canister_users.borrow().is_empty() {
+            return;
+        }
+        let all_canisters = self.all_canisters.borrow();
+        let moved_users = self.moved_users.borrow();
+        let mut canister_users = self.canister_users.borrow_mut();
+        for (user, idx) in self.user_canisters.borrow().iter() {
+            let canister = match moved_users.get(&user) {
+                Some(canister) => Some(canister),
+                None => all_canisters.get(((idx - 1) / USER_PER_SIZE) as u64),
+            };
+            if let Some(canister) = canister {
+                canister_users.insert(CanisterUser(canister.0, user.0), ());
+            }
+        }
+    }
 }
 
 pub fn state_save() {
@@ -128,6 +242,7 @@ pub fn state_restore() {
     STATE.with(|s| {
         let mut state = s.borrow_mut();
         state.reload();
+        state.build_canister_users();
 
         print!(
             "owner: {:?}, helper: {:?}, users: {:?}",
@@ -154,43 +269,71 @@ pub fn get_sim_helper() -> Principal {
 
 pub async fn register_user(user: Principal) -> Result<Principal, String> {
     if check_full() {
-        let canister = create_user_canister(get_sim_helper()).await;
-        match canister {
-            Ok(canister_id) => {
-                let ret = STATE.with(|s| {
-                    let state = s.borrow_mut();
-                    let all_canisters = state.all_canisters.borrow_mut();
-                    all_canisters.push(&StablePrincipal(canister_id)).map(|x| x)
-                });
-                match ret {
-                    Ok(_) => {}
-                    Err(err) => return Err(format!("{:?}", err)),
-                };
+        let canister_id = match take_spare_canister() {
+            Some(canister_id) => canister_id,
+            None => {
+                check_cycles_reserve()?;
+                create_user_canister(get_sim_helper()).await?
             }
-            Err(err) => return Err(err),
         };
+        // a concurrent login may have added a canister during the await,
+        // this one is kept as a spare instead of skipping the free slots
+        if check_full() {
+            add_user_canister(canister_id)?;
+        } else {
+            push_spare_canister(canister_id)?;
+        }
     };
 
     let canister = STATE.with(|s| {
         let mut state = s.borrow_mut();
-        state.sim_state.usercount = state.sim_state.usercount + 1;
+        state.sim_state.usercount += 1;
 
         let all_canisters = state.all_canisters.borrow_mut();
         let mut user_canisters = state.user_canisters.borrow_mut();
         user_canisters.insert(StablePrincipal(user), state.sim_state.usercount);
-        all_canisters
+        let canister = all_canisters
             .get(all_canisters.len() - 1)
-            .expect("can not get the last canister")
+            .expect("can not get the last canister");
+        let mut canister_users = state.canister_users.borrow_mut();
+        canister_users.insert(CanisterUser(canister.0, user), ());
+        canister
     });
     Ok(canister.0)
 }
 
+fn add_user_canister(canister_id: Principal) -> Result<(), String> {
+    STATE.with(|s| {
+        let mut state = s.borrow_mut();
+        let last_retired = {
+            let all_canisters = state.all_canisters.borrow();
+            all_canisters
+                .get(all_canisters.len().saturating_sub(1))
+                .is_some_and(|last| state.retired_canisters.borrow().contains_key(&last))
+        };
+        // skip the free slots left behind by a retired canister
+        if last_retired {
+            let skip_to = USER_PER_SIZE * state.all_canisters.borrow().len() as u128;
+            state.sim_state.usercount = state.sim_state.usercount.max(skip_to);
+        }
+        let all_canisters = state.all_canisters.borrow_mut();
+        all_canisters
+            .push(&StablePrincipal(canister_id))
+            .map_err(|err| format!("{:?}", err))
+    })
+}
+
 pub fn check_full() -> bool {
     STATE.with(|s| {
         let state = s.borrow();
         let all_canisters = state.all_canisters.borrow();
         let canister_len = all_canisters.len() as u128;
 
+        if let Some(last) = all_canisters.get(all_canisters.len().saturating_sub(1)) {
+            if state.retired_canisters.borrow().contains_key(&last) {
+                return true;
+            }
+        }
         state.sim_state.usercount >= USER_PER_SIZE * canister_len
     })
 }
@@ -200,6 +343,9 @@ pub fn get_user_canister(user: Principal) -> Option<Principal> {
         let state = s.borrow();
         let user_canisters = state.user_canisters.borrow();
         let all_canisters = state.all_canisters.borrow();
+        if let Some(canister) = state.moved_users.borrow().get(&StablePrincipal(user)) {
+            return Some(canister.0);
+        }
         let ret = user_canisters.get(&StablePrincipal(user));
 
         match ret {
@@ -221,7 +367,7 @@ pub fn has_canister(canister: Principal) -> bool {
                 return true;
             }
         }
-        return false;
+        false
     })
 }
 
@@ -257,11 +403,463 @@ pub fn get_user_index(user: Principal) -> u128 {
     STATE.with(|s| {
         let state = s.borrow();
         let user_canisters = state.user_canisters.borrow();
-        let ret = user_canisters.get(&StablePrincipal(user));
+        user_canisters
+            .get(&StablePrincipal(user))
+            .unwrap_or_default()
+    })
+}
 
-        match ret {
-            None => 0,
-            Some(idx) => idx,
+fn invite_hash(code: &str) -> InviteHash {
+    Sha256::digest(code.as_bytes()).into()
+}
+
+pub fn set_register_policy(policy: RegisterPolicy) {
+    STATE.with(|s| {
+        let mut state = s.borrow_mut();
+        state.sim_state.register_policy = Some(policy);
+        state.sim_state.register_window = None;
+    });
+}
+
+pub fn get_register_policy() -> RegisterPolicy {
+    STATE.with(|s| {
+        let state = s.borrow();
+        state.sim_state.register_policy.clone().unwrap_or_default()
+    })
+}
+
+pub fn get_register_quota() -> RegisterQuota {
+    STATE.with(|s| {
+        let state = s.borrow();
+        let policy = state.sim_state.register_policy.clone().unwrap_or_default();
+        let window = state.sim_state.register_window.clone().unwrap_or_default();
+        let expired = ic_cdk::api::time() >= window.end(&policy);
+        RegisterQuota {
+            used: if expired { 0 } else { window.count },
+            limit: policy.window_limit,
+            reset_at: if expired { 0 } else { window.end(&policy) },
+        }
+    })
+}
+
+pub fn add_allowlist(users: vec::Vec<Principal>) {
+    STATE.with(|s| {
+        let state = s.borrow();
+        let mut allowlist = state.allowlist.borrow_mut();
+        for user in users {
+            allowlist.insert(StablePrincipal(user), ());
+        }
+    });
+}
+
+pub fn remove_allowlist(users: vec::Vec<Principal>) {
+    STATE.with(|s| {
+        let state = s.borrow();
+        let mut allowlist = state.allowlist.borrow_mut();
+        for user in users {
+            allowlist.remove(&StablePrincipal(user));
+        }
+    });
+}
+
+pub fn add_invite_codes(codes: vec::Vec<String>, uses: u32) {
+    STATE.with(|s| {
+        let state = s.borrow();
+        let mut invite_codes = state.invite_codes.borrow_mut();
+        for code in codes {
+            invite_codes.insert(invite_hash(&code), uses);
+        }
+    });
+}
+
+pub fn remove_invite_codes(codes: vec::Vec<String>) {
+    STATE.with(|s| {
+        let state = s.borrow();
+        let mut invite_codes = state.invite_codes.borrow_mut();
+        for code in codes {
+            invite_codes.remove(&invite_hash(&code));
+        }
+    });
+}
+
+pub struct RegisterSlot {
+    invite: Option<InviteHash>,
+    window_start: Option<u64>,
+}
+
+// Checks the invite and window throttles and reserves a sign-up for `user`.
+// Everything here is synchronous so concurrent logins can not overshoot.
+pub fn take_register_slot(user: Principal, invite: Option<&str>) -> Result<RegisterSlot, String> {
+    STATE.with(|s| {
+        let mut state = s.borrow_mut();
+        let policy = state.sim_state.register_policy.clone().unwrap_or_default();
+        let mut slot = RegisterSlot {
+            invite: None,
+            window_start: None,
+        };
+
+        if policy.invite_only
+            && !state
+                .allowlist
+                .borrow()
+                .contains_key(&StablePrincipal(user))
+        {
+            let hash = match invite {
+                Some(code) => invite_hash(code),
+                None => return Err(ERR_REGISTER_CLOSED.to_string()),
+            };
+            match state.invite_codes.borrow().get(&hash) {
+                Some(uses) if uses > 0 => slot.invite = Some(hash),
+                _ => return Err(ERR_REGISTER_CLOSED.to_string()),
+            }
+        }
+
+        if policy.window_seconds > 0 && policy.window_limit > 0 {
+            let mut window = state.sim_state.register_window.clone().unwrap_or_default();
+            window.admit(&policy, ic_cdk::api::time())?;
+            slot.window_start = Some(window.start);
+            state.sim_state.register_window = Some(window);
+        }
+
+        if let Some(hash) = slot.invite {
+            let mut invite_codes = state.invite_codes.borrow_mut();
+            let uses = invite_codes.get(&hash).unwrap_or_default();
+            invite_codes.insert(hash, uses.saturating_sub(1));
+        }
+        Ok(slot)
+    })
+}
+
+// Gives back a slot taken by `take_register_slot` when the registration failed
+pub fn release_register_slot(slot: RegisterSlot) {
+    STATE.with(|s| {
+        let mut state = s.borrow_mut();
+        if let Some(hash) = slot.invite {
+            let mut invite_codes = state.invite_codes.borrow_mut();
+            if let Some(uses) = invite_codes.get(&hash) {
+                invite_codes.insert(hash, uses + 1);
+            }
+        }
+        if let (Some(start), Some(window)) =
+            (slot.window_start, state.sim_state.register_window.as_mut())
+        {
+            if window.start == start {
+                window.count = window.count.saturating_sub(1);
+            }
+        }
+    });
+}
+
+pub fn set_cycles_floor(floor: u64) {
+    STATE.with(|s| s.borrow_mut().sim_state.cycles_floor = Some(floor));
+}
+
+pub fn get_cycles_floor() -> u64 {
+    STATE.with(|s| {
+        s.borrow()
+            .sim_state
+            .cycles_floor
+            .unwrap_or(DEFAULT_CYCLES_FLOOR)
+    })
+}
+
+pub fn get_capacity() -> Capacity {
+    let balance = ic_cdk::api::canister_balance();
+    let free_slots = if check_full() {
+        0
+    } else {
+        STATE.with(|s| {
+            let state = s.borrow();
+            let canister_len = state.all_canisters.borrow().len() as u128;
+            (USER_PER_SIZE * canister_len).saturating_sub(state.sim_state.usercount) as u64
+        })
+    };
+
+    Capacity::new(balance, get_cycles_floor(), USER_DEFAULT_CYCLES, free_slots)
+}
+
+// Refuses to create a users canister when paying for it would leave
+// users_index below its cycles floor.
+pub fn check_cycles_reserve() -> Result<(), String> {
+    let balance = ic_cdk::api::canister_balance();
+    let capacity = Capacity::new(balance, get_cycles_floor(), USER_DEFAULT_CYCLES, 0);
+    if capacity.creatable == 0 {
+        ic_cdk::print(format!(
+            "refuse to create users canister, balance {} below floor {} + cost {}",
+            balance, capacity.floor, capacity.creation_cost
+        ));
+        return Err(ERR_CAPACITY_UNAVAILABLE.to_string());
+    }
+    Ok(())
+}
+
+pub fn set_pool_size(size: u64) {
+    STATE.with(|s| s.borrow_mut().sim_state.pool_size = Some(size));
+}
+
+pub fn get_pool_size() -> u64 {
+    STATE.with(|s| s.borrow().sim_state.pool_size.unwrap_or_default())
+}
+
+pub fn get_spare_count() -> u64 {
+    STATE.with(|s| s.borrow().spare_canisters.borrow().len())
+}
+
+pub fn get_spare_list() -> vec::Vec<Principal> {
+    STATE.with(|s| {
+        let state = s.borrow();
+        let spare_canisters = state.spare_canisters.borrow();
+        spare_canisters.iter().map(|x| x.0).collect()
+    })
+}
+
+pub fn push_spare_canister(canister: Principal) -> Result<(), String> {
+    STATE.with(|s| {
+        let state = s.borrow();
+        let spare_canisters = state.spare_canisters.borrow_mut();
+        spare_canisters
+            .push(&StablePrincipal(canister))
+            .map_err(|err| format!("{:?}", err))
+    })
+}
+
+pub fn take_spare_canister() -> Option<Principal> {
+    STATE.with(|s| {
+        let state = s.borrow();
+        let spare_canisters = state.spare_canisters.borrow_mut();
+        spare_canisters.pop().map(|x| x.0)
+    })
+}
+
+// Moves the spares built from another users wasm to the uninstalled set, so
+// they get reinstalled with the current one before being handed out.
+pub fn drop_stale_spares(wasm_hash: [u8; 32]) {
+    STATE.with(|s| {
+        let mut state = s.borrow_mut();
+        if state.sim_state.spare_wasm.as_deref() == Some(&wasm_hash[..]) {
+            return;
+        }
+        while let Some(canister) = state.spare_canisters.borrow_mut().pop() {
+            state
+                .uninstalled_canisters
+                .borrow_mut()
+                .insert(canister, ());
+        }
+        state.sim_state.spare_wasm = Some(wasm_hash.to_vec());
+    });
+}
+
+pub fn set_retired(canister: Principal, retired: bool) {
+    STATE.with(|s| {
+        let state = s.borrow();
+        let mut retired_canisters = state.retired_canisters.borrow_mut();
+        if retired {
+            retired_canisters.insert(StablePrincipal(canister), ());
+        } else {
+            retired_canisters.remove(&StablePrincipal(canister));
+        }
+    });
+}
+
+pub fn is_retired(canister: Principal) -> bool {
+    STATE.with(|s| {
+        let state = s.borrow();
+        let retired_canisters = state.retired_canisters.borrow();
+        retired_canisters.contains_key(&StablePrincipal(canister))
+    })
+}
+
+pub fn get_retired_list() -> vec::Vec<Principal> {
+    STATE.with(|s| {
+        let state = s.borrow();
+        let retired_canisters = state.retired_canisters.borrow();
+        retired_canisters.iter().map(|(k, _)| k.0).collect()
+    })
+}
+
+pub fn push_uninstalled(canister: Principal) {
+    STATE.with(|s| {
+        let state = s.borrow();
+        let mut uninstalled_canisters = state.uninstalled_canisters.borrow_mut();
+        uninstalled_canisters.insert(StablePrincipal(canister), ());
+    });
+}
+
+pub fn take_uninstalled() -> Option<Principal> {
+    STATE.with(|s| {
+        let state = s.borrow();
+        let mut uninstalled_canisters = state.uninstalled_canisters.borrow_mut();
+        let canister = uninstalled_canisters.iter().next().map(|(k, _)| k)?;
+        uninstalled_canisters.remove(&canister);
+        Some(canister.0)
+    })
+}
+
+pub fn get_uninstalled_list() -> vec::Vec<Principal> {
+    STATE.with(|s| {
+        let state = s.borrow();
+        let uninstalled_canisters = state.uninstalled_canisters.borrow();
+        uninstalled_canisters.iter().map(|(k, _)| k.0).collect()
+    })
+}
+
+// Points `user` at `canister`, dropping the override when it is the
+// canister the user's index already maps to.
+pub fn set_user_canister(user: Principal, canister: Principal) {
+    let current = get_user_canister(user);
+    STATE.with(|s| {
+        let state = s.borrow();
+        let all_canisters = state.all_canisters.borrow();
+        let mut moved_users = state.moved_users.borrow_mut();
+        let home = state
+            .user_canisters
+            .borrow()
+            .get(&StablePrincipal(user))
+            .and_then(|idx| all_canisters.get(((idx - 1) / USER_PER_SIZE) as u64));
+
+        match home {
+            Some(home) if home.0 == canister => {
+                moved_users.remove(&StablePrincipal(user));
+            }
+            _ => {
+                moved_users.insert(StablePrincipal(user), StablePrincipal(canister));
+            }
+        }
+
+        let mut canister_users = state.canister_users.borrow_mut();
+        if let Some(current) = current {
+            canister_users.remove(&CanisterUser(current, user));
         }
+        canister_users.insert(CanisterUser(canister, user), ());
+    });
+}
+
+// Users currently served by `canister`, at most `limit` of them
+pub fn get_canister_users(canister: Principal, limit: usize) -> vec::Vec<Principal> {
+    STATE.with(|s| {
+        let state = s.borrow();
+        let canister_users = state.canister_users.borrow();
+        let start = CanisterUser(canister, Principal::from_slice(&[]));
+        canister_users
+            .range(start..)
+            .map(|(k, _)| k)
+            .take_while(|k| k.0 == canister)
+            .map(|k| k.1)
+            .take(limit)
+            .collect()
     })
 }
+
+pub fn get_canister_user_count(canister: Principal) -> u64 {
+    STATE.with(|s| {
+        let state = s.borrow();
+        let canister_users = state.canister_users.borrow();
+        let start = CanisterUser(canister, Principal::from_slice(&[]));
+        canister_users
+            .range(start..)
+            .take_while(|(k, _)| k.0 == canister)
+            .count() as u64
+    })
+}
+
+pub fn get_canister_events(canister: Principal) -> CanisterEvents {
+    STATE.with(|s| {
+        let state = s.borrow();
+        let canister_events = state.canister_events.borrow();
+        canister_events
+            .get(&StablePrincipal(canister))
+            .unwrap_or_default()
+    })
+}
+
+fn update_canister_events(canister: Principal, f: impl FnOnce(&mut CanisterEvents)) {
+    STATE.with(|s| {
+        let state = s.borrow();
+        let mut canister_events = state.canister_events.borrow_mut();
+        let mut events = canister_events
+            .get(&StablePrincipal(canister))
+            .unwrap_or_default();
+        f(&mut events);
+        canister_events.insert(StablePrincipal(canister), events);
+    });
+}
+
+pub fn record_canister_upgrade(canister: Principal) {
+    update_canister_events(canister, |events| {
+        events.last_upgrade = ic_cdk::api::time();
+    });
+}
+
+pub fn record_canister_error(canister: Principal, error: &str) {
+    let mut error = error.to_string();
+    if error.len() > MAX_ERROR_LEN {
+        let mut end = MAX_ERROR_LEN;
+        while !error.is_char_boundary(end) {
+            end -= 1;
+        }
+        error.truncate(end);
+    }
+    update_canister_events(canister, |events| {
+        events.last_error = Some(error);
+        events.last_error_at = ic_cdk::api::time();
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn canister(id: u8) -> Principal {
+        Principal::from_slice(&[id])
+    }
+
+    fn usercount() -> u128 {
+        STATE.with(|s| s.borrow().sim_state.usercount)
+    }
+
+    #[test]
+    fn a_new_canister_skips_only_the_slots_of_a_retired_one() {
+        add_user_canister(canister(1)).unwrap();
+        STATE.with(|s| s.borrow_mut().sim_state.usercount = USER_PER_SIZE - 1);
+        assert!(!check_full());
+
+        set_retired(canister(1), true);
+        assert!(check_full());
+        add_user_canister(canister(2)).unwrap();
+        assert_eq!(usercount(), USER_PER_SIZE);
+        assert!(!check_full());
+    }
+
+    #[test]
+    fn a_new_canister_keeps_the_count_when_the_last_is_not_retired() {
+        add_user_canister(canister(1)).unwrap();
+        STATE.with(|s| s.borrow_mut().sim_state.usercount = 3);
+        add_user_canister(canister(2)).unwrap();
+        assert_eq!(usercount(), 3);
+    }
+
+    #[test]
+    fn spares_from_another_wasm_go_to_the_uninstalled_set() {
+        drop_stale_spares([1; 32]);
+        push_spare_canister(canister(1)).unwrap();
+        drop_stale_spares([1; 32]);
+        assert_eq!(get_spare_list(), vec![canister(1)]);
+
+        drop_stale_spares([2; 32]);
+        assert_eq!(get_spare_count(), 0);
+        assert_eq!(take_uninstalled(), Some(canister(1)));
+    }
+
+    #[test]
+    fn user_counts_come_from_the_canister_index() {
+        set_user_canister(canister(10), canister(1));
+        set_user_canister(canister(11), canister(1));
+        set_user_canister(canister(12), canister(2));
+        assert_eq!(get_canister_user_count(canister(1)), 2);
+
+        set_user_canister(canister(11), canister(2));
+        assert_eq!(get_canister_user_count(canister(1)), 1);
+        assert_eq!(get_canister_user_count(canister(2)), 2);
+        assert_eq!(get_canister_user_count(canister(3)), 0);
+    }
+}
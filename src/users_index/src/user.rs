@@ -1,7 +1,7 @@
 // This is an experimental feature to generate Rust binding from Candid.
 // You may want to manually adjust some of the types
 
-use candid::Int;
+use candid::{Int, Nat};
 use ic_cdk::api::call::CallResult;
 use ic_cdk::export::candid::{CandidType, Deserialize};
 use ic_cdk::export::{candid, Principal};
@@ -18,7 +18,9 @@ pub enum PlanetMsgType {
     Remove,
 }
 
-#[derive(CandidType, Deserialize)]
+// Named as in the candid interface of the users canister
+#[allow(clippy::upper_case_acronyms)]
+#[derive(CandidType, Deserialize, Clone)]
 pub struct NFT {
     token_index: String,
     canister_id: Principal,
@@ -34,6 +36,31 @@ pub struct UserInfo {
     avatar: String,
 }
 
+#[derive(CandidType, Deserialize, Clone)]
+pub struct Attribute {
+    key: String,
+    value: String,
+}
+
+#[derive(CandidType, Deserialize, Clone)]
+pub struct Collection {
+    canister_id: Principal,
+    article_id: String,
+}
+
+#[derive(CandidType, Deserialize, Clone)]
+pub struct UserData {
+    pub pid: Principal,
+    avatar: String,
+    nft: Option<NFT>,
+    email: String,
+    created: Int,
+    planets: Vec<Principal>,
+    subscribes: Vec<Principal>,
+    collections: Vec<Collection>,
+    attributes: Vec<Attribute>,
+}
+
 #[derive(CandidType, Deserialize, Debug)]
 pub struct PlanetMsg {
     pub msg_type: PlanetMsgType,
@@ -56,4 +83,19 @@ impl UserService {
     ) -> CallResult<(bool,)> {
         ic_cdk::call(self.0, "on_planet_msg", (arg0, arg1)).await
     }
+    pub async fn canister_memory(&self) -> CallResult<(Nat,)> {
+        ic_cdk::call(self.0, "canister_memory", ()).await
+    }
+    pub async fn export_user(&self, arg0: candid::Principal) -> CallResult<(Option<UserData>,)> {
+        ic_cdk::call(self.0, "export_user", (arg0,)).await
+    }
+    pub async fn import_user(&self, arg0: UserData) -> CallResult<(bool,)> {
+        ic_cdk::call(self.0, "import_user", (arg0,)).await
+    }
+    pub async fn remove_user(&self, arg0: candid::Principal) -> CallResult<(bool,)> {
+        ic_cdk::call(self.0, "remove_user", (arg0,)).await
+    }
+    pub async fn unlock_user(&self, arg0: candid::Principal) -> CallResult<(bool,)> {
+        ic_cdk::call(self.0, "unlock_user", (arg0,)).await
+    }
 }